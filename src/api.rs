@@ -1,37 +1,384 @@
-use reqwest::{
-    blocking::{Client, RequestBuilder},
-    header::USER_AGENT,
-    Url,
-};
+use base64::{engine::general_purpose::STANDARD as base64, Engine};
+use reqwest::StatusCode;
+use reqwest::{header::USER_AGENT, RequestBuilder, Url};
+use serde::{de::DeserializeOwned, Deserialize, Deserializer, Serialize};
 use serde_json::{json, Value};
 use std::collections::HashMap;
+use std::fmt;
 
-const API_VERSION: &str = "4.43.0";
+use crate::auth::User;
+
+pub const BASE_URL: &str = "https://api.ecoledirecte.com/";
+pub const API_VERSION: &str = "4.43.0";
+pub const USER_AGENT_NAME: &str = "ecoledirecte-imap";
+// L'application web, dont on peut déduire la version de l'API
+pub const WEB_URL: &str = "https://www.ecoledirecte.com/";
+
+// Comment joindre l'API. EcoleDirecte change régulièrement la version
+// demandée (`v=`) et refuse parfois les User-Agent qu'elle ne connaît pas.
+#[derive(Clone, Debug)]
+pub struct Settings {
+    pub base_url: Url,
+    // "auto" pour la chercher au démarrage (voir `detect_version`)
+    pub version: String,
+    pub user_agent: String,
+}
+
+impl Default for Settings {
+    fn default() -> Settings {
+        Settings {
+            // unwrap: l'adresse est valide
+            base_url: Url::parse(BASE_URL).unwrap(),
+            version: API_VERSION.to_string(),
+            user_agent: USER_AGENT_NAME.to_string(),
+        }
+    }
+}
+
+// Le client HTTP et les réglages de l'API
+pub struct Client {
+    http: reqwest::Client,
+    base_url: Url,
+    version: String,
+    user_agent: String,
+}
+
+impl Client {
+    pub async fn new(settings: Settings) -> Client {
+        let http = reqwest::Client::new();
+        let mut base_url = settings.base_url;
+        // Les routes sont relatives à l'adresse, qui peut avoir un chemin
+        if !base_url.path().ends_with('/') {
+            base_url.set_path(&format!("{}/", base_url.path()));
+        }
+        let version = match settings.version.as_str() {
+            "auto" => detect_version(&http, &settings.user_agent)
+                .await
+                .unwrap_or_else(|| {
+                    log::warn!(
+                        "Version de l'API introuvable, on utilise {} par défaut",
+                        API_VERSION
+                    );
+                    API_VERSION.to_string()
+                }),
+            version => version.to_string(),
+        };
+        Client {
+            http,
+            base_url,
+            version,
+            user_agent: settings.user_agent,
+        }
+    }
+}
+
+// Les scripts référencés par une page HTML
+fn scripts(page: &str) -> impl Iterator<Item = &str> {
+    page.split("src=\"")
+        .skip(1)
+        .filter_map(|rest| rest.split('"').next())
+        .filter(|src| src.ends_with(".js"))
+}
+
+// Une version ("4.43.0") qui suit `apiVersion` dans un script
+fn find_version(source: &str) -> Option<String> {
+    source
+        .match_indices("apiVersion")
+        .find_map(|(position, _)| {
+            let rest = &source[position..];
+            let start = rest.find(['"', '\''])? + 1;
+            let end = start + rest[start..].find(['"', '\''])?;
+            let version = &rest[start..end];
+            let valid = !version.is_empty()
+                && version.contains('.')
+                && version.chars().all(|c| c.is_ascii_digit() || c == '.');
+            valid.then(|| version.to_string())
+        })
+}
+
+// L'application web envoie la version attendue par l'API : on la cherche dans
+// ses scripts. Ce n'est pas une interface stable, d'où la version par défaut.
+pub async fn detect_version(http: &reqwest::Client, user_agent: &str) -> Option<String> {
+    let get = |url: Url| async move {
+        let response = http.get(url).header(USER_AGENT, user_agent).send().await;
+        response.ok()?.error_for_status().ok()?.text().await.ok()
+    };
+    let web_url = Url::parse(WEB_URL).ok()?;
+    let page = get(web_url.clone()).await?;
+    for script in scripts(&page).filter_map(|script| web_url.join(script).ok()) {
+        if let Some(version) = get(script).await.as_deref().and_then(find_version) {
+            return Some(version);
+        }
+    }
+    None
+}
 
 fn build_request<'a>(
-    client: &Client,
+    client: &'a Client,
     verbe: &'a str,
     route: &str,
     mut qs_params: HashMap<&str, &'a str>,
     json_params: Value,
     token: &str,
 ) -> RequestBuilder {
-    let BASE_URL = Url::parse("https://api.ecoledirecte.com/").unwrap();
     qs_params.insert("verbe", verbe);
-    qs_params.insert("v", API_VERSION);
-    let url = Url::parse_with_params(BASE_URL.join(route).unwrap().as_str(), qs_params).unwrap();
+    qs_params.insert("v", &client.version);
+    // unwrap: les routes sont des chemins valides
+    let route = client.base_url.join(route.trim_start_matches('/')).unwrap();
+    let url = Url::parse_with_params(route.as_str(), qs_params).unwrap();
     client
+        .http
         .post(url)
-        .header(USER_AGENT, "ecoledirecte-imap")
+        .header(USER_AGENT, &client.user_agent)
         .header("X-Token", token)
         .body("data=".to_owned() + &json_params.to_string())
 }
 
-pub fn login(
+#[derive(Debug)]
+pub enum ApiError {
+    // La requête n'a pas abouti (réseau, TLS, délai dépassé...)
+    Transport(reqwest::Error),
+    // Le serveur a répondu par une erreur HTTP
+    Status(StatusCode),
+    // EcoleDirecte a répondu avec un code d'erreur (identifiants invalides...)
+    EcoleDirecte { code: u64, message: String },
+    // La réponse n'a pas la forme attendue
+    Schema(String),
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ApiError::Transport(error) => write!(f, "EcoleDirecte is unreachable: {}", error),
+            ApiError::Status(status) => write!(f, "EcoleDirecte answered {}", status),
+            ApiError::EcoleDirecte { code, message } if message.is_empty() => {
+                write!(f, "EcoleDirecte error {}", code)
+            }
+            ApiError::EcoleDirecte { message, .. } => write!(f, "{}", message),
+            ApiError::Schema(error) => write!(f, "Unexpected EcoleDirecte response: {}", error),
+        }
+    }
+}
+
+impl std::error::Error for ApiError {}
+
+impl From<reqwest::Error> for ApiError {
+    fn from(error: reqwest::Error) -> ApiError {
+        ApiError::Transport(error)
+    }
+}
+
+impl From<serde_json::Error> for ApiError {
+    fn from(error: serde_json::Error) -> ApiError {
+        ApiError::Schema(error.to_string())
+    }
+}
+
+// EcoleDirecte renvoie parfois null à la place d'une chaîne ou d'une liste vide
+fn nullable<'de, D: Deserializer<'de>, T: Default + Deserialize<'de>>(
+    deserializer: D,
+) -> Result<T, D::Error> {
+    Ok(Option::deserialize(deserializer)?.unwrap_or_default())
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct Account {
+    pub id: u32,
+}
+
+#[derive(Debug, Deserialize)]
+struct Login {
+    accounts: Vec<Account>,
+}
+
+// Un dossier créé par l'utilisateur
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Classeur {
+    pub id: u32,
+    pub libelle: String,
+}
+
+// Les compteurs de messages de chaque dossier
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Pagination {
+    #[serde(rename = "messagesRecusCount")]
+    pub received: u32,
+    #[serde(rename = "messagesRecusNotReadCount")]
+    pub received_unread: u32,
+    #[serde(rename = "messagesEnvoyesCount")]
+    pub sent: u32,
+    #[serde(rename = "messagesArchivesCount")]
+    pub archived: u32,
+    #[serde(rename = "messagesDraftCount")]
+    pub draft: u32,
+}
+
+// L'expéditeur ou un destinataire d'un message
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Person {
+    #[serde(deserialize_with = "nullable")]
+    pub civilite: String,
+    #[serde(deserialize_with = "nullable")]
+    pub prenom: String,
+    #[serde(deserialize_with = "nullable")]
+    pub particule: String,
+    #[serde(deserialize_with = "nullable")]
+    pub nom: String,
+    #[serde(deserialize_with = "nullable")]
+    pub name: String,
+    #[serde(deserialize_with = "nullable")]
+    pub role: String,
+    pub id: u64,
+}
+
+// Un message tel qu'il apparaît dans la liste d'un dossier
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MessageSummary {
+    pub id: u64,
+    // Au format "2023-10-15 18:30:00", heure de Paris
+    #[serde(default, deserialize_with = "nullable")]
+    pub date: String,
+    #[serde(default, deserialize_with = "nullable")]
+    pub subject: String,
+    #[serde(default)]
+    pub read: bool,
+    #[serde(default)]
+    pub answered: bool,
+    #[serde(default)]
+    pub from: Option<Person>,
+    #[serde(default, deserialize_with = "nullable")]
+    pub to: Vec<Person>,
+    // Drapeaux qu'EcoleDirecte ne connaît pas, conservés localement (voir `Store`)
+    #[serde(default, rename = "localFlags", skip_serializing_if = "Vec::is_empty")]
+    pub local_flags: Vec<String>,
+}
+
+// Une pièce jointe
+#[derive(Clone, Debug, Deserialize)]
+pub struct File {
+    pub id: u64,
+    #[serde(default, deserialize_with = "nullable")]
+    pub libelle: String,
+    #[serde(default, rename = "type", deserialize_with = "nullable")]
+    pub kind: String,
+}
+
+// Un message complet
+#[derive(Clone, Debug, Deserialize)]
+pub struct Message {
+    #[serde(flatten)]
+    pub summary: MessageSummary,
+    // Du HTML, décodé par `get_message`
+    #[serde(default, deserialize_with = "nullable")]
+    pub content: String,
+    #[serde(default, deserialize_with = "nullable")]
+    pub files: Vec<File>,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Messages {
+    #[serde(deserialize_with = "nullable")]
+    pub received: Vec<MessageSummary>,
+    #[serde(deserialize_with = "nullable")]
+    pub sent: Vec<MessageSummary>,
+    #[serde(deserialize_with = "nullable")]
+    pub archived: Vec<MessageSummary>,
+    #[serde(deserialize_with = "nullable")]
+    pub draft: Vec<MessageSummary>,
+}
+
+// Réponse de l'API pour un dossier. Seule la liste du type demandé (voir
+// `mailbox::kind`) est remplie.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Folder {
+    #[serde(deserialize_with = "nullable")]
+    pub classeurs: Vec<Classeur>,
+    pub messages: Messages,
+    pub pagination: Pagination,
+}
+
+// Codes renvoyés par l'API quand le jeton n'est plus valable
+const EXPIRED_TOKEN: [u64; 2] = [520, 525];
+
+// Envoie une requête au nom de l'utilisateur et renvoie le corps de la
+// réponse. Chaque réponse apporte un nouveau jeton qui remplace le précédent ;
+// si le jeton a expiré, on se reconnecte et on recommence une fois.
+async fn send<'a>(
+    client: &Client,
+    user: &User,
+    verbe: &'a str,
+    route: &str,
+    qs_params: HashMap<&str, &'a str>,
+    json_params: Value,
+) -> Result<Vec<u8>, ApiError> {
+    let mut retried = false;
+    loop {
+        let token = user.token();
+        let request = build_request(
+            client,
+            verbe,
+            route,
+            qs_params.clone(),
+            json_params.clone(),
+            &token,
+        );
+        let response = request.send().await?;
+        if !response.status().is_success() {
+            return Err(ApiError::Status(response.status()));
+        }
+        let body = response.bytes().await?.to_vec();
+
+        // Les pièces jointes ne sont pas du JSON
+        let Ok(response) = serde_json::from_slice::<Value>(&body) else {
+            return Ok(body);
+        };
+        if let Some(token) = response["token"].as_str().filter(|token| !token.is_empty()) {
+            user.set_token(token.to_string());
+        }
+
+        let expired = response["code"]
+            .as_u64()
+            .is_some_and(|code| EXPIRED_TOKEN.contains(&code));
+        if expired && !retried {
+            retried = true;
+            let (username, password) = user.credentials();
+            if let Ok((_, token)) = login(client, username, password).await {
+                user.set_token(token);
+                continue;
+            }
+        }
+        return Ok(body);
+    }
+}
+
+// Erreur si le code de la réponse n'est pas 200
+fn check(response: &Value) -> Result<(), ApiError> {
+    match response["code"].as_u64() {
+        Some(200) => Ok(()),
+        Some(code) => Err(ApiError::EcoleDirecte {
+            code,
+            message: response["message"].as_str().unwrap_or("").to_string(),
+        }),
+        None => Err(ApiError::Schema(String::from("missing code"))),
+    }
+}
+
+// Les données de la réponse, après vérification du code
+fn data<T: DeserializeOwned>(body: &[u8]) -> Result<T, ApiError> {
+    let mut response: Value = serde_json::from_slice(body)?;
+    check(&response)?;
+    Ok(serde_json::from_value(response["data"].take())?)
+}
+
+pub async fn login(
     client: &Client,
     username: &str,
     password: &str,
-) -> Result<(u32, String), Option<String>> {
+) -> Result<(u32, String), ApiError> {
     let request = build_request(
         client,
         "",
@@ -43,49 +390,244 @@ pub fn login(
         }),
         "",
     );
-    let response: Value = request.send().unwrap().json().unwrap();
-
-    if response["code"] == json!(200) {
-        Ok((
-            response["data"]["accounts"][0]["id"]
-                .as_u64()
-                .unwrap()
-                .try_into()
-                .unwrap(),
-            response["token"].as_str().unwrap().to_string(),
-        ))
-    } else {
-        Err(response["message"].as_str().map(|s: &str| s.to_string()))
+    let response = request.send().await?;
+    if !response.status().is_success() {
+        return Err(ApiError::Status(response.status()));
     }
+    let mut response: Value = serde_json::from_slice(&response.bytes().await?)?;
+    check(&response)?;
+
+    let login: Login = serde_json::from_value(response["data"].take())?;
+    let account = login
+        .accounts
+        .first()
+        .ok_or_else(|| ApiError::Schema(String::from("no account")))?;
+    let token = response["token"]
+        .as_str()
+        .ok_or_else(|| ApiError::Schema(String::from("missing token")))?;
+    Ok((account.id, token.to_string()))
 }
 
-pub fn get_folder_info(client: &Client, mailbox_id: u32, user_id: u32, token: &str) -> Value {
+pub async fn get_folder_info(
+    client: &Client,
+    mailbox_id: u32,
+    kind: &str,
+    user: &User,
+) -> Result<Folder, ApiError> {
     let mailbox_id = mailbox_id.to_string();
-    let request = build_request(
+    let response = send(
         client,
+        user,
         "get",
-        &format!("/v3/eleves/{user_id}/messages.awp"),
+        &format!("/v3/eleves/{}/messages.awp", user.id),
         {
             let mut qs = HashMap::<&str, &str>::new();
             qs.insert("idClasseur", &mailbox_id);
+            qs.insert("typeRecuperation", kind);
+            qs.insert("getAll", "1");
             qs
         },
         json!({}),
-        token,
-    );
-    request.send().unwrap().json::<Value>().unwrap()["data"].take()
-}
-
-pub fn get_folders(client: &Client, id: u32, token: &str) -> Vec<(String, u32)> {
-    get_folder_info(client, 0, id, token)["classeurs"]
-        .as_array()
-        .unwrap()
-        .into_iter()
-        .map(|classeur| {
-            (
-                classeur["libelle"].as_str().unwrap().to_string(),
-                classeur["id"].as_u64().unwrap() as u32,
-            )
+    )
+    .await?;
+    data(&response)
+}
+
+pub async fn get_message(
+    client: &Client,
+    message_id: u64,
+    mode: &str,
+    user: &User,
+) -> Result<Message, ApiError> {
+    let response = send(
+        client,
+        user,
+        "get",
+        &format!("/v3/eleves/{}/messages/{}.awp", user.id, message_id),
+        {
+            let mut qs = HashMap::<&str, &str>::new();
+            qs.insert("mode", mode);
+            qs
+        },
+        json!({}),
+    )
+    .await?;
+    let mut message: Message = data(&response)?;
+
+    // Le contenu (du HTML) est encodé en base64
+    let content = base64
+        .decode(&message.content)
+        .map_err(|error| ApiError::Schema(format!("content: {}", error)))?;
+    message.content = String::from_utf8_lossy(&content).into_owned();
+    Ok(message)
+}
+
+// Requête sur la messagerie dont on n'attend que le code de retour
+async fn action(
+    client: &Client,
+    verbe: &str,
+    json_params: Value,
+    user: &User,
+) -> Result<(), ApiError> {
+    let response = send(
+        client,
+        user,
+        verbe,
+        &format!("/v3/eleves/{}/messages.awp", user.id),
+        HashMap::new(),
+        json_params,
+    )
+    .await?;
+    check(&serde_json::from_slice(&response)?)
+}
+
+// Marque les messages comme lus ou non lus
+pub async fn mark_read(
+    client: &Client,
+    message_ids: &[u64],
+    read: bool,
+    user: &User,
+) -> Result<(), ApiError> {
+    action(
+        client,
+        "put",
+        json!({
+            "action": if read { "marquerCommeLu" } else { "marquerCommeNonLu" },
+            "ids": message_ids,
+        }),
+        user,
+    )
+    .await
+}
+
+// Déplace des messages (voir `mailbox::move_action`)
+pub async fn move_messages(
+    client: &Client,
+    message_ids: &[u64],
+    action: &str,
+    classeur_id: u32,
+    user: &User,
+) -> Result<(), ApiError> {
+    self::action(
+        client,
+        "put",
+        json!({
+            "action": action,
+            "ids": message_ids,
+            "idClasseur": classeur_id,
+        }),
+        user,
+    )
+    .await
+}
+
+pub async fn create_classeur(client: &Client, name: &str, user: &User) -> Result<(), ApiError> {
+    action(
+        client,
+        "post",
+        json!({ "action": "creerClasseur", "libelle": name }),
+        user,
+    )
+    .await
+}
+
+pub async fn rename_classeur(
+    client: &Client,
+    classeur_id: u32,
+    name: &str,
+    user: &User,
+) -> Result<(), ApiError> {
+    action(
+        client,
+        "put",
+        json!({ "action": "renommerClasseur", "idClasseur": classeur_id, "libelle": name }),
+        user,
+    )
+    .await
+}
+
+pub async fn delete_classeur(
+    client: &Client,
+    classeur_id: u32,
+    user: &User,
+) -> Result<(), ApiError> {
+    action(
+        client,
+        "delete",
+        json!({ "action": "supprimerClasseur", "idClasseur": classeur_id }),
+        user,
+    )
+    .await
+}
+
+pub async fn get_attachment(
+    client: &Client,
+    file_id: u64,
+    file_type: &str,
+    user: &User,
+) -> Result<Vec<u8>, ApiError> {
+    let file_id = file_id.to_string();
+    let body = send(
+        client,
+        user,
+        "get",
+        "/v3/telechargement.awp",
+        {
+            let mut qs = HashMap::<&str, &str>::new();
+            qs.insert("fichierId", &file_id);
+            qs.insert("leTypeDeFichier", file_type);
+            qs
+        },
+        json!({ "forceDownload": 0 }),
+    )
+    .await?;
+    // En cas d'erreur (jeton refusé malgré la reconnexion...), l'API répond
+    // en JSON à la place du fichier
+    if let Ok(response) = serde_json::from_slice::<Value>(&body) {
+        if response.get("code").is_some() {
+            check(&response)?;
+        }
+    }
+    Ok(body)
+}
+
+// Un message à envoyer : le contenu est du HTML
+pub struct NewMessage {
+    pub to: Vec<Person>,
+    pub subject: String,
+    pub content: String,
+}
+
+pub async fn send_message(
+    client: &Client,
+    message: &NewMessage,
+    user: &User,
+) -> Result<(), ApiError> {
+    // Chaque destinataire garde son rôle et son identifiant (voir `Person`)
+    let destinataires: Vec<_> = message
+        .to
+        .iter()
+        .map(|person| {
+            json!({
+                "id": person.id,
+                "type": person.role,
+                "to_cc_cci": "to",
+            })
         })
-        .collect()
+        .collect();
+    action(
+        client,
+        "post",
+        json!({
+            "message": {
+                "groupesDestinataires": [{ "destinataires": destinataires }],
+                "subject": message.subject,
+                "content": base64.encode(&message.content),
+                "transfertFiles": [],
+                "files": [],
+            },
+        }),
+        user,
+    )
+    .await
 }
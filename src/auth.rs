@@ -5,13 +5,43 @@ use imap_codec::imap_types::{
     state::State,
 };
 use std::str;
+use std::sync::Mutex;
 
-use crate::capabilities;
+use crate::api::ApiError;
+use crate::{capabilities, code, failure};
 
-#[derive(Clone)]
+// L'utilisateur connecté. Les identifiants servent à se reconnecter quand le
+// jeton expire, et le jeton change à chaque réponse de l'API (voir `api::send`).
 pub struct User {
     pub id: u32,
-    pub token: String,
+    token: Mutex<String>,
+    username: String,
+    password: String,
+}
+
+impl User {
+    pub fn new(id: u32, token: String, username: &str, password: &str) -> User {
+        User {
+            id,
+            token: Mutex::new(token),
+            username: username.to_string(),
+            password: password.to_string(),
+        }
+    }
+
+    pub fn token(&self) -> String {
+        // unwrap: personne ne panique en tenant le verrou
+        self.token.lock().unwrap().clone()
+    }
+
+    pub fn set_token(&self, token: String) {
+        // unwrap: personne ne panique en tenant le verrou
+        *self.token.lock().unwrap() = token;
+    }
+
+    pub fn credentials(&self) -> (&str, &str) {
+        (&self.username, &self.password)
+    }
 }
 
 pub fn parse_plain_message<'a, 'b>(
@@ -45,14 +75,15 @@ pub fn parse_plain_message<'a, 'b>(
 
 // Pas sûr de comment il faut nommer cette fonction puisqu'elle ne fait que
 // traduire le résultat de l'API en action concrètes dans le système.
-pub fn translate(
-    authentification_result: Result<(u32, String), Option<String>>,
-    tag: Tag,
-) -> (State<'static>, Option<User>, Vec<Response<'_>>) {
+pub fn translate<'a>(
+    authentification_result: Result<(u32, String), ApiError>,
+    (username, password): (&str, &str),
+    tag: Tag<'a>,
+) -> (State<'static>, Option<User>, Vec<Response<'a>>) {
     match authentification_result {
         Ok((id, token)) => (
             State::Authenticated,
-            Some(User { id, token }),
+            Some(User::new(id, token, username, password)),
             vec![Response::Status(
                 Status::ok(
                     Some(tag),
@@ -62,20 +93,26 @@ pub fn translate(
                 .unwrap(),
             )],
         ),
-        Err(message) => (
+        // Identifiants refusés par EcoleDirecte
+        Err(ApiError::EcoleDirecte { message, .. }) => (
             State::NotAuthenticated,
             None,
             vec![Response::Status(
                 Status::no(
-                    Some(tag),
-                    None,
-                    match message {
-                        Some(message) => format!("Authentication failed: {}", message),
-                        None => String::from("Authentication failed"),
-                    },
+                    Some(tag.clone()),
+                    Some(code("AUTHENTICATIONFAILED")),
+                    format!("Authentication failed: {}", message),
                 )
-                .unwrap(),
+                .unwrap_or_else(|_| {
+                    Status::no(
+                        Some(tag),
+                        Some(code("AUTHENTICATIONFAILED")),
+                        "Authentication failed",
+                    )
+                    .unwrap()
+                }),
             )],
         ),
+        Err(error) => (State::NotAuthenticated, None, vec![failure(tag, &error)]),
     }
 }
@@ -0,0 +1,156 @@
+#[cfg(test)]
+pub mod fake;
+
+use std::future::Future;
+
+use crate::api::{self, ApiError, Client, File, Folder, Message, NewMessage};
+use crate::auth::User;
+
+// Ce dont le serveur IMAP a besoin de la messagerie. L'implémentation
+// normale est `EcoleDirecte`, les tests utilisent `fake::Fake`.
+//
+// Les méthodes renvoient des futures `Send` pour que chaque connexion puisse
+// tourner dans sa propre tâche tokio.
+pub trait MailBackend: Send + Sync {
+    // L'identifiant du compte et un jeton
+    fn login(
+        &self,
+        username: &str,
+        password: &str,
+    ) -> impl Future<Output = Result<(u32, String), ApiError>> + Send;
+
+    // Les classeurs et les messages d'un dossier (voir `mailbox::kind`)
+    fn folder(
+        &self,
+        user: &User,
+        classeur_id: u32,
+        kind: &str,
+    ) -> impl Future<Output = Result<Folder, ApiError>> + Send;
+
+    // Le message complet (voir `mailbox::mode`), qui est alors marqué comme lu
+    fn message(
+        &self,
+        user: &User,
+        message_id: u64,
+        mode: &str,
+    ) -> impl Future<Output = Result<Message, ApiError>> + Send;
+
+    fn attachment(
+        &self,
+        user: &User,
+        file: &File,
+    ) -> impl Future<Output = Result<Vec<u8>, ApiError>> + Send;
+
+    fn mark_read(
+        &self,
+        user: &User,
+        message_ids: &[u64],
+        read: bool,
+    ) -> impl Future<Output = Result<(), ApiError>> + Send;
+
+    // Voir `mailbox::move_action`
+    fn move_messages(
+        &self,
+        user: &User,
+        message_ids: &[u64],
+        action: &str,
+        classeur_id: u32,
+    ) -> impl Future<Output = Result<(), ApiError>> + Send;
+
+    fn create_classeur(
+        &self,
+        user: &User,
+        name: &str,
+    ) -> impl Future<Output = Result<(), ApiError>> + Send;
+
+    fn rename_classeur(
+        &self,
+        user: &User,
+        classeur_id: u32,
+        name: &str,
+    ) -> impl Future<Output = Result<(), ApiError>> + Send;
+
+    fn delete_classeur(
+        &self,
+        user: &User,
+        classeur_id: u32,
+    ) -> impl Future<Output = Result<(), ApiError>> + Send;
+
+    fn send(
+        &self,
+        user: &User,
+        message: &NewMessage,
+    ) -> impl Future<Output = Result<(), ApiError>> + Send;
+}
+
+pub struct EcoleDirecte {
+    client: Client,
+}
+
+impl EcoleDirecte {
+    pub fn new(client: Client) -> EcoleDirecte {
+        EcoleDirecte { client }
+    }
+}
+
+impl MailBackend for EcoleDirecte {
+    async fn login(&self, username: &str, password: &str) -> Result<(u32, String), ApiError> {
+        api::login(&self.client, username, password).await
+    }
+
+    async fn folder(&self, user: &User, classeur_id: u32, kind: &str) -> Result<Folder, ApiError> {
+        api::get_folder_info(&self.client, classeur_id, kind, user).await
+    }
+
+    async fn message(&self, user: &User, message_id: u64, mode: &str) -> Result<Message, ApiError> {
+        api::get_message(&self.client, message_id, mode, user).await
+    }
+
+    async fn attachment(&self, user: &User, file: &File) -> Result<Vec<u8>, ApiError> {
+        let kind = match file.kind.as_str() {
+            "" => "PIECE_JOINTE",
+            kind => kind,
+        };
+        api::get_attachment(&self.client, file.id, kind, user).await
+    }
+
+    async fn mark_read(
+        &self,
+        user: &User,
+        message_ids: &[u64],
+        read: bool,
+    ) -> Result<(), ApiError> {
+        api::mark_read(&self.client, message_ids, read, user).await
+    }
+
+    async fn move_messages(
+        &self,
+        user: &User,
+        message_ids: &[u64],
+        action: &str,
+        classeur_id: u32,
+    ) -> Result<(), ApiError> {
+        api::move_messages(&self.client, message_ids, action, classeur_id, user).await
+    }
+
+    async fn create_classeur(&self, user: &User, name: &str) -> Result<(), ApiError> {
+        api::create_classeur(&self.client, name, user).await
+    }
+
+    async fn rename_classeur(
+        &self,
+        user: &User,
+        classeur_id: u32,
+        name: &str,
+    ) -> Result<(), ApiError> {
+        api::rename_classeur(&self.client, classeur_id, name, user).await
+    }
+
+    async fn delete_classeur(&self, user: &User, classeur_id: u32) -> Result<(), ApiError> {
+        api::delete_classeur(&self.client, classeur_id, user).await
+    }
+
+    async fn send(&self, user: &User, message: &NewMessage) -> Result<(), ApiError> {
+        api::send_message(&self.client, message, user).await
+    }
+}
@@ -0,0 +1,343 @@
+use reqwest::StatusCode;
+use std::sync::{Mutex, MutexGuard};
+
+use super::MailBackend;
+use crate::api::{
+    ApiError, Classeur, File, Folder, Message, MessageSummary, Messages, NewMessage, Pagination,
+    Person,
+};
+use crate::auth::User;
+
+pub const USERNAME: &str = "eleve";
+pub const PASSWORD: &str = "secret";
+
+struct Stored {
+    message: Message,
+    // Voir `mailbox::kind`
+    kind: &'static str,
+    classeur: u32,
+}
+
+#[derive(Default)]
+struct State {
+    classeurs: Vec<Classeur>,
+    messages: Vec<Stored>,
+    // Simule une panne d'EcoleDirecte
+    down: bool,
+    // Messages dont EcoleDirecte refuse de changer l'état lu / non lu
+    locked: Vec<u64>,
+}
+
+// Une messagerie EcoleDirecte en mémoire, pour tester le serveur sans réseau
+pub struct Fake {
+    account: u32,
+    state: Mutex<State>,
+}
+
+fn refused(message: &str) -> ApiError {
+    ApiError::EcoleDirecte {
+        code: 210,
+        message: message.to_string(),
+    }
+}
+
+impl Fake {
+    // Chaque test utilise son propre compte pour ne pas partager le `Store`
+    pub fn new(account: u32) -> Fake {
+        Fake {
+            account,
+            state: Mutex::default(),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        // unwrap: un test qui a paniqué a déjà échoué
+        self.state.lock().unwrap()
+    }
+
+    fn available(&self) -> Result<MutexGuard<'_, State>, ApiError> {
+        let state = self.lock();
+        match state.down {
+            true => Err(ApiError::Status(StatusCode::SERVICE_UNAVAILABLE)),
+            false => Ok(state),
+        }
+    }
+
+    // Ajoute un message reçu dans l'INBOX
+    pub fn receive(&self, id: u64, subject: &str, read: bool) {
+        let summary = MessageSummary {
+            id,
+            date: format!("2023-10-{:02} 10:00:00", id % 28 + 1),
+            subject: subject.to_string(),
+            read,
+            answered: false,
+            from: Some(Person {
+                civilite: String::from("M."),
+                prenom: String::from("Jean"),
+                nom: String::from("Dupont"),
+                role: String::from("P"),
+                id: 12,
+                ..Person::default()
+            }),
+            to: vec![],
+            local_flags: vec![],
+        };
+        self.lock().messages.push(Stored {
+            message: Message {
+                summary,
+                content: format!("<p>{}</p>", subject),
+                files: vec![],
+            },
+            kind: "received",
+            classeur: 0,
+        });
+    }
+
+    pub fn add_classeur(&self, id: u32, libelle: &str) {
+        self.lock().classeurs.push(Classeur {
+            id,
+            libelle: libelle.to_string(),
+        });
+    }
+
+    // Le message a reçu une réponse
+    pub fn answer(&self, id: u64) {
+        for stored in &mut self.lock().messages {
+            if stored.message.summary.id == id {
+                stored.message.summary.answered = true;
+            }
+        }
+    }
+
+    // Déplace le message sans passer par le serveur, comme depuis le site
+    pub fn relocate(&self, id: u64, kind: &'static str, classeur: u32) {
+        for stored in &mut self.lock().messages {
+            if stored.message.summary.id == id {
+                (stored.kind, stored.classeur) = (kind, classeur);
+            }
+        }
+    }
+
+    // Le message est lu, ou remis comme non lu, depuis le site
+    pub fn set_read(&self, id: u64, read: bool) {
+        for stored in &mut self.lock().messages {
+            if stored.message.summary.id == id {
+                stored.message.summary.read = read;
+            }
+        }
+    }
+
+    pub fn lock_message(&self, id: u64) {
+        self.lock().locked.push(id);
+    }
+
+    pub fn set_down(&self, down: bool) {
+        self.lock().down = down;
+    }
+
+    pub fn is_read(&self, id: u64) -> bool {
+        self.lock()
+            .messages
+            .iter()
+            .any(|stored| stored.message.summary.id == id && stored.message.summary.read)
+    }
+
+    // Le type et le classeur du message
+    pub fn location(&self, id: u64) -> Option<(&'static str, u32)> {
+        self.lock()
+            .messages
+            .iter()
+            .find(|stored| stored.message.summary.id == id)
+            .map(|stored| (stored.kind, stored.classeur))
+    }
+
+    pub fn classeurs(&self) -> Vec<String> {
+        self.lock()
+            .classeurs
+            .iter()
+            .map(|classeur| classeur.libelle.clone())
+            .collect()
+    }
+
+    pub fn sent(&self) -> Vec<String> {
+        self.lock()
+            .messages
+            .iter()
+            .filter(|stored| stored.kind == "sent")
+            .map(|stored| stored.message.summary.subject.clone())
+            .collect()
+    }
+}
+
+impl MailBackend for Fake {
+    async fn login(&self, username: &str, password: &str) -> Result<(u32, String), ApiError> {
+        let _state = self.available()?;
+        match (username, password) {
+            (USERNAME, PASSWORD) => Ok((self.account, String::from("jeton"))),
+            _ => Err(ApiError::EcoleDirecte {
+                code: 505,
+                message: String::from("Identifiant et/ou mot de passe invalide !"),
+            }),
+        }
+    }
+
+    async fn folder(&self, _user: &User, classeur_id: u32, kind: &str) -> Result<Folder, ApiError> {
+        let state = self.available()?;
+        let listed: Vec<_> = state
+            .messages
+            .iter()
+            .filter(|stored| stored.kind == kind)
+            .filter(|stored| kind != "received" || stored.classeur == classeur_id)
+            .map(|stored| stored.message.summary.clone())
+            .collect();
+
+        let count = listed.len() as u32;
+        let mut pagination = Pagination::default();
+        let mut messages = Messages::default();
+        match kind {
+            "sent" => (pagination.sent, messages.sent) = (count, listed),
+            "archived" => (pagination.archived, messages.archived) = (count, listed),
+            "draft" => (pagination.draft, messages.draft) = (count, listed),
+            _ => {
+                pagination.received_unread =
+                    listed.iter().filter(|message| !message.read).count() as u32;
+                (pagination.received, messages.received) = (count, listed);
+            }
+        }
+        Ok(Folder {
+            classeurs: state.classeurs.clone(),
+            messages,
+            pagination,
+        })
+    }
+
+    async fn message(
+        &self,
+        _user: &User,
+        message_id: u64,
+        _mode: &str,
+    ) -> Result<Message, ApiError> {
+        let mut state = self.available()?;
+        let stored = state
+            .messages
+            .iter_mut()
+            .find(|stored| stored.message.summary.id == message_id)
+            .ok_or_else(|| refused("Message introuvable"))?;
+        // Comme EcoleDirecte, le message est marqué comme lu
+        stored.message.summary.read = true;
+        Ok(stored.message.clone())
+    }
+
+    async fn attachment(&self, _user: &User, file: &File) -> Result<Vec<u8>, ApiError> {
+        let _state = self.available()?;
+        Ok(format!("contenu de {}", file.libelle).into_bytes())
+    }
+
+    async fn mark_read(
+        &self,
+        _user: &User,
+        message_ids: &[u64],
+        read: bool,
+    ) -> Result<(), ApiError> {
+        let mut state = self.available()?;
+        if message_ids.iter().any(|id| state.locked.contains(id)) {
+            return Err(refused("Action impossible sur ce message"));
+        }
+        for stored in &mut state.messages {
+            if message_ids.contains(&stored.message.summary.id) {
+                stored.message.summary.read = read;
+            }
+        }
+        Ok(())
+    }
+
+    async fn move_messages(
+        &self,
+        _user: &User,
+        message_ids: &[u64],
+        action: &str,
+        classeur_id: u32,
+    ) -> Result<(), ApiError> {
+        let mut state = self.available()?;
+        let (kind, classeur) = match action {
+            "archiver" => ("archived", 0),
+            "desarchiver" => ("received", 0),
+            "deplacer" => ("received", classeur_id),
+            _ => return Err(refused("Action inconnue")),
+        };
+        for stored in &mut state.messages {
+            if message_ids.contains(&stored.message.summary.id) {
+                (stored.kind, stored.classeur) = (kind, classeur);
+            }
+        }
+        Ok(())
+    }
+
+    async fn create_classeur(&self, _user: &User, name: &str) -> Result<(), ApiError> {
+        let mut state = self.available()?;
+        let id = state.classeurs.iter().map(|classeur| classeur.id).max();
+        state.classeurs.push(Classeur {
+            id: id.unwrap_or(0) + 1,
+            libelle: name.to_string(),
+        });
+        Ok(())
+    }
+
+    async fn rename_classeur(
+        &self,
+        _user: &User,
+        classeur_id: u32,
+        name: &str,
+    ) -> Result<(), ApiError> {
+        let mut state = self.available()?;
+        let classeur = state
+            .classeurs
+            .iter_mut()
+            .find(|classeur| classeur.id == classeur_id)
+            .ok_or_else(|| refused("Classeur introuvable"))?;
+        classeur.libelle = name.to_string();
+        Ok(())
+    }
+
+    async fn delete_classeur(&self, _user: &User, classeur_id: u32) -> Result<(), ApiError> {
+        let mut state = self.available()?;
+        state
+            .classeurs
+            .retain(|classeur| classeur.id != classeur_id);
+        // Les messages du classeur retournent dans l'INBOX
+        for stored in &mut state.messages {
+            if stored.kind == "received" && stored.classeur == classeur_id {
+                stored.classeur = 0;
+            }
+        }
+        Ok(())
+    }
+
+    async fn send(&self, _user: &User, message: &NewMessage) -> Result<(), ApiError> {
+        let mut state = self.available()?;
+        let id = state
+            .messages
+            .iter()
+            .map(|stored| stored.message.summary.id)
+            .max();
+        state.messages.push(Stored {
+            message: Message {
+                summary: MessageSummary {
+                    id: id.unwrap_or(0) + 1,
+                    date: String::from("2023-10-01 12:00:00"),
+                    subject: message.subject.clone(),
+                    read: true,
+                    answered: false,
+                    from: None,
+                    to: message.to.clone(),
+                    local_flags: vec![],
+                },
+                content: message.content.clone(),
+                files: vec![],
+            },
+            kind: "sent",
+            classeur: 0,
+        });
+        Ok(())
+    }
+}
@@ -0,0 +1,301 @@
+use bytes::{Buf, BytesMut};
+use imap_codec::{
+    decode::{AuthenticateDataDecodeError, CommandDecodeError, Decoder as _, IdleDoneDecodeError},
+    encode::Encoder as _,
+    imap_types::{
+        auth::AuthenticateData,
+        bounded_static::IntoBoundedStatic,
+        command::Command,
+        core::{LiteralMode, Tag},
+        response::{Greeting, Response},
+    },
+    AuthenticateDataCodec, CommandCodec, GreetingCodec, IdleDoneCodec, ResponseCodec,
+};
+use std::io;
+use std::str;
+use tokio_util::codec::{Decoder, Encoder};
+
+use crate::{config, mailbox};
+
+// Assez pour reconnaître l'annonce d'un littéral, "{4294967295+}\r", à la fin
+// d'une ligne dont on ignore le début
+const ANNOUNCEMENT_SIZE: usize = 14;
+
+// Ce que le serveur attend du client. Toutes les données passent par le même
+// tampon : celles qui suivent AUTHENTICATE ou IDLE ne sont pas perdues si le
+// client les envoie avec la commande.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Expected {
+    #[default]
+    Command,
+    // La réponse à une demande de continuation d'AUTHENTICATE
+    AuthenticateData,
+    // DONE, pendant IDLE
+    IdleDone,
+}
+
+#[derive(Debug)]
+pub enum Frame {
+    Command {
+        command: Command<'static>,
+        // LIST (SPECIAL-USE), voir `mailbox::list_extended`
+        list_special_use: bool,
+    },
+    AuthenticateData(AuthenticateData),
+    IdleDone,
+    // Littéral synchrone annoncé : le client attend une demande de continuation
+    LiteralAccepted,
+    // Littéral plus grand que `server.max_literal_size`, ou qui ferait
+    // dépasser `server.max_command_size` : la commande est abandonnée
+    LiteralTooBig {
+        tag: Option<Tag<'static>>,
+        mode: LiteralMode,
+    },
+    // Ligne plus longue que `server.max_command_size`, ignorée jusqu'à la fin
+    CommandTooLong {
+        tag: Option<Tag<'static>>,
+    },
+    // Données incompréhensibles, déjà retirées du tampon
+    Failed,
+}
+
+// Les commandes, les données d'AUTHENTICATE et DONE du client, et les
+// réponses du serveur
+#[derive(Debug, Default)]
+pub struct ImapCodec {
+    expected: Expected,
+    list_special_use: bool,
+    // Position de la fin du dernier littéral synchrone accepté, pour ne pas
+    // envoyer deux fois la demande de continuation
+    accepted: Option<usize>,
+    // Octets restants du littéral non synchrone d'une commande refusée : ils
+    // sont ignorés, puis le reste de la commande (jusqu'à la fin de la ligne
+    // s'il n'y en a pas)
+    discarding: Option<usize>,
+}
+
+fn find_line_end(src: &[u8]) -> Option<usize> {
+    src.windows(2).position(|window| window == b"\r\n")
+}
+
+// Retire la ligne en cours (ou tout le tampon, s'il n'y a pas de fin de ligne)
+fn skip_line(src: &mut BytesMut) {
+    let line_end = find_line_end(src).map_or(src.len(), |end| end + 2);
+    src.advance(line_end);
+}
+
+// La ligne en cours, terminée ou non, dépasse `server.max_command_size`
+fn line_too_long(src: &[u8]) -> bool {
+    find_line_end(src).map_or(src.len(), |end| end + 2) > config::get().server.max_command_size
+}
+
+// Annonce d'un littéral à la fin d'une ligne : {42}, ou {42+} pour LITERAL+
+fn literal_announcement(line: &[u8]) -> Option<(u32, LiteralMode)> {
+    let inner = line.strip_suffix(b"}")?;
+    let start = inner.iter().rposition(|&byte| byte == b'{')?;
+    let (digits, mode) = match inner[start + 1..].strip_suffix(b"+") {
+        Some(digits) => (digits, LiteralMode::NonSync),
+        None => (&inner[start + 1..], LiteralMode::Sync),
+    };
+    if digits.is_empty() || !digits.iter().all(u8::is_ascii_digit) {
+        return None;
+    }
+    Some((str::from_utf8(digits).ok()?.parse().ok()?, mode))
+}
+
+// L'étiquette de la commande au début du tampon, s'il y en a une valide
+fn command_tag(src: &[u8]) -> Option<Tag<'static>> {
+    let end = src.iter().position(|&byte| byte == b' ')?;
+    Tag::try_from(String::from_utf8(src[..end].to_vec()).ok()?).ok()
+}
+
+impl ImapCodec {
+    pub fn expect(&mut self, expected: Expected) {
+        self.expected = expected;
+    }
+
+    // Retire la commande refusée, littéraux compris. Renvoie faux s'il en
+    // reste à recevoir.
+    fn discard(&mut self, src: &mut BytesMut) -> bool {
+        while let Some(remaining) = self.discarding {
+            let skipped = remaining.min(src.len());
+            src.advance(skipped);
+            self.discarding = Some(remaining - skipped);
+            if skipped < remaining {
+                return false;
+            }
+            // La suite de la commande peut annoncer un autre littéral (un
+            // littéral synchrone n'arrivera pas : on ne l'a pas accepté)
+            let Some(line_end) = find_line_end(src) else {
+                src.advance(src.len().saturating_sub(ANNOUNCEMENT_SIZE));
+                return false;
+            };
+            self.discarding = match literal_announcement(&src[..line_end]) {
+                Some((length, LiteralMode::NonSync)) => Some(length as usize),
+                _ => None,
+            };
+            src.advance(line_end + 2);
+        }
+        true
+    }
+
+    // La ligne en cours est trop longue : on l'ignorera
+    fn too_long(&mut self, src: &mut BytesMut, tag: Option<Tag<'static>>) -> Frame {
+        self.discarding = Some(0);
+        self.discard(src);
+        self.accepted = None;
+        self.list_special_use = false;
+        Frame::CommandTooLong { tag }
+    }
+
+    fn decode_command(&mut self, src: &mut BytesMut) -> Option<Frame> {
+        // Les options de LIST-EXTENDED sont retirées avant le décodage
+        if let Some(line_end) = find_line_end(src) {
+            if let Some((line, special_use)) = mailbox::list_extended(&src[..line_end]) {
+                let mut rewritten = BytesMut::from(&line[..]);
+                rewritten.extend_from_slice(&src[line_end..]);
+                *src = rewritten;
+                self.list_special_use = special_use;
+            }
+        }
+
+        // La commande s'arrête à la première ligne qui n'annonce pas de
+        // littéral : chaque annonce est suivie des données puis d'une ligne.
+        // Les littéraux comptent pour la taille maximale, pour qu'on ne puisse
+        // pas en enchaîner autant qu'on veut.
+        let max_size = config::get().server.max_command_size;
+        let mut size = 0;
+        let mut position = 0;
+        let command_end = loop {
+            let line_end = find_line_end(&src[position..]).map(|end| position + end);
+            size += line_end.map_or(src.len(), |end| end + 2) - position;
+            if size > max_size {
+                let tag = command_tag(src);
+                src.advance(position);
+                return Some(self.too_long(src, tag));
+            }
+            let line_end = line_end?;
+            let Some((length, mode)) = literal_announcement(&src[position..line_end]) else {
+                break line_end + 2;
+            };
+
+            if length > config::get().server.max_literal_size || size + length as usize > max_size {
+                let tag = command_tag(src);
+                src.advance(line_end + 2);
+                // Les données d'un littéral non synchrone sont déjà en route
+                if mode == LiteralMode::NonSync {
+                    self.discarding = Some(length as usize);
+                }
+                self.accepted = None;
+                self.list_special_use = false;
+                return Some(Frame::LiteralTooBig { tag, mode });
+            }
+
+            position = line_end + 2;
+            if mode == LiteralMode::Sync && src.len() == position && self.accepted != Some(position)
+            {
+                self.accepted = Some(position);
+                return Some(Frame::LiteralAccepted);
+            }
+            size += length as usize;
+            position += length as usize;
+            if src.len() < position {
+                return None;
+            }
+        };
+        self.accepted = None;
+
+        match CommandCodec::default().decode(&src[..command_end]) {
+            Ok((_, command)) => {
+                let command = command.into_static();
+                src.advance(command_end);
+                Some(Frame::Command {
+                    command,
+                    list_special_use: std::mem::take(&mut self.list_special_use),
+                })
+            }
+            // La commande est complète : les littéraux ont déjà été acceptés
+            Err(
+                CommandDecodeError::Failed
+                | CommandDecodeError::Incomplete
+                | CommandDecodeError::LiteralFound { .. },
+            ) => {
+                src.advance(command_end);
+                self.list_special_use = false;
+                Some(Frame::Failed)
+            }
+        }
+    }
+
+    fn decode_authenticate_data(&mut self, src: &mut BytesMut) -> Option<Frame> {
+        if line_too_long(src) {
+            return Some(self.too_long(src, None));
+        }
+        match AuthenticateDataCodec::default().decode(src) {
+            Ok((remaining, data)) => {
+                let consumed = src.len() - remaining.len();
+                src.advance(consumed);
+                Some(Frame::AuthenticateData(data))
+            }
+            Err(AuthenticateDataDecodeError::Incomplete) => None,
+            // Y compris "*", qu'AuthenticateDataCodec ne gère pas
+            Err(AuthenticateDataDecodeError::Failed) => {
+                skip_line(src);
+                Some(Frame::Failed)
+            }
+        }
+    }
+
+    fn decode_idle_done(&mut self, src: &mut BytesMut) -> Option<Frame> {
+        if line_too_long(src) {
+            return Some(self.too_long(src, None));
+        }
+        match IdleDoneCodec::default().decode(src) {
+            Ok((remaining, _)) => {
+                let consumed = src.len() - remaining.len();
+                src.advance(consumed);
+                Some(Frame::IdleDone)
+            }
+            Err(IdleDoneDecodeError::Incomplete) => None,
+            // La ligne n'est pas DONE : on l'ignore
+            Err(IdleDoneDecodeError::Failed) => {
+                skip_line(src);
+                Some(Frame::Failed)
+            }
+        }
+    }
+}
+
+impl Decoder for ImapCodec {
+    type Item = Frame;
+    type Error = io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Frame>, io::Error> {
+        if !self.discard(src) || src.is_empty() {
+            return Ok(None);
+        }
+        Ok(match self.expected {
+            Expected::Command => self.decode_command(src),
+            Expected::AuthenticateData => self.decode_authenticate_data(src),
+            Expected::IdleDone => self.decode_idle_done(src),
+        })
+    }
+}
+
+impl Encoder<&Greeting<'_>> for ImapCodec {
+    type Error = io::Error;
+
+    fn encode(&mut self, greeting: &Greeting<'_>, dst: &mut BytesMut) -> Result<(), io::Error> {
+        dst.extend_from_slice(&GreetingCodec::default().encode(greeting).dump());
+        Ok(())
+    }
+}
+
+impl Encoder<&Response<'_>> for ImapCodec {
+    type Error = io::Error;
+
+    fn encode(&mut self, response: &Response<'_>, dst: &mut BytesMut) -> Result<(), io::Error> {
+        dst.extend_from_slice(&ResponseCodec::default().encode(response).dump());
+        Ok(())
+    }
+}
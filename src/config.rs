@@ -0,0 +1,334 @@
+use log::LevelFilter;
+use reqwest::Url;
+use serde::Deserialize;
+use std::env;
+use std::fs;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+use crate::api;
+use crate::mailbox::DELIMITER;
+
+// Le fichier de configuration, par exemple :
+//
+//   [server]
+//   listen = ["127.0.0.1", "::1"]
+//   port = 1993
+//
+//   [folders]
+//   sent = "Envoyés"
+//
+// Toutes les valeurs sont facultatives. Les variables d'environnement
+// ECOLEDIRECTE_IMAP_* ont la priorité sur le fichier (voir `apply_env`), et
+// les options de la ligne de commande sur les deux.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: Server,
+    pub tls: Tls,
+    pub api: Api,
+    pub polling: Polling,
+    pub storage: Storage,
+    pub log: Log,
+    pub folders: Folders,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Server {
+    // Adresses IPv4 ou IPv6 sur lesquelles écouter
+    pub listen: Vec<String>,
+    pub port: u16,
+    // Taille maximale d'un littéral envoyé par le client, en octets
+    pub max_literal_size: u32,
+    // Taille maximale d'une commande, littéraux compris, en octets
+    pub max_command_size: usize,
+}
+
+impl Default for Server {
+    fn default() -> Server {
+        Server {
+            listen: vec![String::from("127.0.0.1")],
+            port: 1993,
+            max_literal_size: 64 * 1024,
+            max_command_size: 256 * 1024,
+        }
+    }
+}
+
+// Sans certificat, les connexions restent en clair
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Tls {
+    // Fichiers PEM, relus à la réception de SIGHUP
+    pub certificate: Option<PathBuf>,
+    pub key: Option<PathBuf>,
+    // Port IMAPS (TLS dès la connexion), en plus de STARTTLS sur `server.port`
+    pub port: Option<u16>,
+    // Accepter LOGIN et AUTHENTICATE sur une connexion en clair alors que
+    // STARTTLS est disponible
+    pub plaintext_login: bool,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Api {
+    pub url: Option<String>,
+    // "auto" pour la chercher au démarrage
+    pub version: Option<String>,
+    pub user_agent: Option<String>,
+}
+
+// En secondes
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Polling {
+    // Intervalle entre deux interrogations de l'API pendant IDLE
+    pub idle_interval: u64,
+    // Durée pendant laquelle la liste des messages d'un dossier reste en cache
+    pub cache_max_age: u64,
+}
+
+impl Default for Polling {
+    fn default() -> Polling {
+        Polling {
+            idle_interval: 60,
+            cache_max_age: 60,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Storage {
+    // Par défaut $XDG_DATA_HOME/ecoledirecte-imap
+    pub data_dir: Option<PathBuf>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Log {
+    // off, error, warn, info, debug ou trace
+    pub level: String,
+}
+
+impl Default for Log {
+    fn default() -> Log {
+        Log {
+            level: String::from("info"),
+        }
+    }
+}
+
+// Noms affichés des dossiers d'EcoleDirecte (l'INBOX s'appelle toujours INBOX)
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Folders {
+    pub sent: String,
+    pub archive: String,
+    pub drafts: String,
+}
+
+impl Default for Folders {
+    fn default() -> Folders {
+        Folders {
+            sent: String::from("Sent"),
+            archive: String::from("Archived"),
+            drafts: String::from("Drafts"),
+        }
+    }
+}
+
+static CONFIG: OnceLock<Config> = OnceLock::new();
+
+// La configuration du serveur, celle par défaut si `set` n'a pas été appelée
+pub fn get() -> &'static Config {
+    CONFIG.get_or_init(|| {
+        let mut config = Config::default();
+        if let Err(error) = config.apply_env() {
+            log::warn!("{}", error);
+        }
+        config
+    })
+}
+
+// À appeler au démarrage, avant toute utilisation de `get`
+pub fn set(config: Config) {
+    if CONFIG.set(config).is_err() {
+        log::warn!("La configuration a déjà été utilisée, elle ne change plus");
+    }
+}
+
+// Emplacement par défaut du fichier de configuration
+pub fn default_path() -> Option<PathBuf> {
+    match (env::var_os("XDG_CONFIG_HOME"), env::var_os("HOME")) {
+        (Some(config), _) => Some(PathBuf::from(config).join("ecoledirecte-imap/config.toml")),
+        (None, Some(home)) => {
+            Some(PathBuf::from(home).join(".config/ecoledirecte-imap/config.toml"))
+        }
+        (None, None) => None,
+    }
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Config, String> {
+        let contents = fs::read_to_string(path)
+            .map_err(|error| format!("Impossible de lire {}: {}", path.display(), error))?;
+        toml::from_str(&contents).map_err(|error| format!("{}: {}", path.display(), error))
+    }
+
+    pub fn log_level(&self) -> Option<LevelFilter> {
+        self.log.level.parse().ok()
+    }
+
+    // Les erreurs qui empêcheraient le serveur de fonctionner correctement
+    pub fn validate(&self) -> Vec<String> {
+        let mut errors = vec![];
+
+        if self.server.listen.is_empty() {
+            errors.push(String::from("server.listen: aucune adresse"));
+        }
+        for address in &self.server.listen {
+            if address.parse::<IpAddr>().is_err() {
+                errors.push(format!("server.listen: adresse invalide « {} »", address));
+            }
+        }
+        if self.server.port == 0 {
+            errors.push(String::from("server.port: le port ne peut pas être 0"));
+        }
+        // LITERAL- promet d'accepter les littéraux non synchrones jusqu'à 4096
+        // octets (voir `capabilities`)
+        if self.server.max_literal_size < 4096 {
+            errors.push(String::from(
+                "server.max_literal_size: au moins 4096 octets",
+            ));
+        }
+        if self.server.max_command_size < 1024 {
+            errors.push(String::from(
+                "server.max_command_size: au moins 1024 octets",
+            ));
+        }
+
+        match (&self.tls.certificate, &self.tls.key) {
+            (Some(_), None) => {
+                errors.push(String::from("tls.key: il faut une clé avec le certificat"))
+            }
+            (None, Some(_)) => errors.push(String::from(
+                "tls.certificate: il faut un certificat avec la clé",
+            )),
+            (None, None) if self.tls.port.is_some() => {
+                errors.push(String::from("tls.port: il faut un certificat et une clé"))
+            }
+            _ => (),
+        }
+        match self.tls.port {
+            Some(0) => errors.push(String::from("tls.port: le port ne peut pas être 0")),
+            Some(port) if port == self.server.port => errors.push(String::from(
+                "tls.port: le port est déjà celui de server.port",
+            )),
+            _ => (),
+        }
+
+        if let Some(url) = &self.api.url {
+            if let Err(error) = Url::parse(url) {
+                errors.push(format!("api.url: {}", error));
+            }
+        }
+
+        if self.polling.idle_interval == 0 {
+            errors.push(String::from("polling.idle_interval: doit être positif"));
+        }
+        if self.log_level().is_none() {
+            errors.push(format!("log.level: niveau inconnu « {} »", self.log.level));
+        }
+
+        let folders = [
+            ("folders.sent", &self.folders.sent),
+            ("folders.archive", &self.folders.archive),
+            ("folders.drafts", &self.folders.drafts),
+        ];
+        for (index, (key, name)) in folders.iter().enumerate() {
+            if name.is_empty() || name.eq_ignore_ascii_case("INBOX") || name.contains(DELIMITER) {
+                errors.push(format!("{}: nom de dossier invalide « {} »", key, name));
+            }
+            if folders[..index].iter().any(|(_, other)| other == name) {
+                errors.push(format!("{}: « {} » est déjà utilisé", key, name));
+            }
+        }
+        errors
+    }
+
+    // Remplace les valeurs du fichier par celles des variables d'environnement :
+    // ECOLEDIRECTE_IMAP_API_URL (pour un faux serveur de tests par exemple),
+    // ECOLEDIRECTE_IMAP_API_VERSION ("auto" pour la détecter)...
+    pub fn apply_env(&mut self) -> Result<(), String> {
+        if let Some(dir) = env::var_os("ECOLEDIRECTE_IMAP_DATA") {
+            self.storage.data_dir = Some(PathBuf::from(dir));
+        }
+        if let Ok(seconds) = env::var("ECOLEDIRECTE_IMAP_IDLE_INTERVAL") {
+            self.polling.idle_interval = seconds.parse().map_err(|_| {
+                format!(
+                    "ECOLEDIRECTE_IMAP_IDLE_INTERVAL: nombre de secondes invalide ({})",
+                    seconds
+                )
+            })?;
+        }
+        if let Ok(url) = env::var("ECOLEDIRECTE_IMAP_API_URL") {
+            self.api.url = Some(url);
+        }
+        if let Ok(version) = env::var("ECOLEDIRECTE_IMAP_API_VERSION") {
+            self.api.version = Some(version);
+        }
+        if let Ok(user_agent) = env::var("ECOLEDIRECTE_IMAP_USER_AGENT") {
+            self.api.user_agent = Some(user_agent);
+        }
+        Ok(())
+    }
+
+    // Réglages de l'API
+    pub fn api_settings(&self) -> api::Settings {
+        let mut settings = api::Settings::default();
+        if let Some(url) = self.api.url.as_ref().and_then(|url| Url::parse(url).ok()) {
+            settings.base_url = url;
+        }
+        if let Some(version) = &self.api.version {
+            settings.version = version.clone();
+        }
+        if let Some(user_agent) = &self.api.user_agent {
+            settings.user_agent = user_agent.clone();
+        }
+        settings
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_config_is_valid() {
+        assert_eq!(Config::default().validate(), Vec::<String>::new());
+    }
+
+    #[test]
+    fn limits_are_checked() {
+        let mut config = Config::default();
+        config.server.listen.clear();
+        config.server.max_literal_size = 4095;
+        config.server.max_command_size = 1023;
+        assert_eq!(
+            config.validate(),
+            [
+                "server.listen: aucune adresse",
+                "server.max_literal_size: au moins 4096 octets",
+                "server.max_command_size: au moins 1024 octets",
+            ]
+        );
+
+        config.server.listen = vec![String::from("::1")];
+        config.server.max_literal_size = 4096;
+        config.server.max_command_size = 1024;
+        assert!(config.validate().is_empty());
+    }
+}
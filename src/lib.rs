@@ -1,10 +1,102 @@
 pub mod api;
 pub mod auth;
+pub mod backend;
+pub mod codec;
+pub mod config;
 pub mod mailbox;
+pub mod message;
+pub mod mime;
+pub mod rfc5322;
+pub mod search;
+pub mod server;
+pub mod store;
+pub mod tls;
+pub mod uid;
 
-use imap_codec::imap_types::{core::NonEmptyVec, response::Capability};
+// Le client IMAP des tests, le même que pour les tests de bout en bout
+#[cfg(test)]
+#[path = "../tests/common/mod.rs"]
+mod common;
+
+use imap_codec::imap_types::{
+    core::{NonEmptyVec, Tag},
+    response::{Capability, Code, CodeOther, Response, Status},
+};
+use std::env;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use api::ApiError;
 
 pub fn capabilities() -> NonEmptyVec<Capability<'static>> {
-    use imap_codec::imap_types::{auth::AuthMechanism::*, response::Capability::*};
-    NonEmptyVec::try_from(vec![Imap4Rev1, Auth(Plain)]).unwrap()
+    use imap_codec::imap_types::{auth::AuthMechanism::*, core::Atom, response::Capability::*};
+    // LITERAL- (RFC 7888) limite les littéraux non synchrones à 4096 octets :
+    // on l'annonce plutôt que LITERAL+ si on n'accepte pas plus (jamais moins,
+    // voir `Config::validate`)
+    let literal = if config::get().server.max_literal_size > 4096 {
+        LiteralPlus
+    } else {
+        LiteralMinus
+    };
+    NonEmptyVec::try_from(vec![
+        Imap4Rev1,
+        Auth(Plain),
+        literal,
+        Move,
+        Idle,
+        Capability::from(Atom::try_from("SPECIAL-USE").unwrap()),
+    ])
+    .unwrap()
+}
+
+// Code de réponse qu'imap-types ne connaît pas (CANNOT, ALREADYEXISTS... de
+// la RFC 5530, COPYUID de la RFC 4315)
+pub fn code(code: impl Into<Vec<u8>>) -> Code<'static> {
+    Code::Other(CodeOther::unvalidated(code.into()))
+}
+
+// Réponse à une commande qui a échoué à cause de l'API : [UNAVAILABLE] quand
+// EcoleDirecte est injoignable, [SERVERBUG] quand sa réponse est incomprise et
+// son propre message quand elle refuse la requête
+pub fn failure<'a>(tag: Tag<'a>, error: &ApiError) -> Response<'a> {
+    log::warn!("Erreur de l'API : {}", error);
+    let code = match error {
+        ApiError::Transport(_) | ApiError::Status(_) => Some(code("UNAVAILABLE")),
+        ApiError::Schema(_) => Some(code("SERVERBUG")),
+        ApiError::EcoleDirecte { .. } => None,
+    };
+    // Le message d'EcoleDirecte peut contenir n'importe quoi
+    let text: String = error
+        .to_string()
+        .chars()
+        .map(|c| if c.is_control() { ' ' } else { c })
+        .collect();
+    Response::Status(
+        Status::no(Some(tag.clone()), code.clone(), text)
+            .or_else(|_| Status::no(Some(tag), code, "EcoleDirecte error"))
+            // unwrap: le texte de remplacement est valide
+            .unwrap(),
+    )
+}
+
+// Dossier où sont conservées les données entre deux connexions
+pub fn data_dir() -> PathBuf {
+    if let Some(dir) = &config::get().storage.data_dir {
+        return dir.clone();
+    }
+    match (env::var_os("XDG_DATA_HOME"), env::var_os("HOME")) {
+        (Some(data), _) => PathBuf::from(data).join("ecoledirecte-imap"),
+        (None, Some(home)) => PathBuf::from(home).join(".local/share/ecoledirecte-imap"),
+        (None, None) => PathBuf::from(".ecoledirecte-imap"),
+    }
+}
+
+// Réglages de l'API (voir `Config::apply_env` pour les variables d'environnement)
+pub fn api_settings() -> api::Settings {
+    config::get().api_settings()
+}
+
+// Intervalle entre deux interrogations de l'API pendant IDLE
+pub fn idle_interval() -> Duration {
+    Duration::from_secs(config::get().polling.idle_interval)
 }
@@ -1,94 +1,561 @@
-use chrono::{Datelike, Local};
+use base64::{engine::general_purpose::STANDARD_NO_PAD as base64, Engine};
+use imap_codec::imap_types::sequence::{SeqOrUid, Sequence, SequenceSet};
 use imap_codec::imap_types::{
-    flag::{Flag, FlagPerm},
+    command::CommandBody,
+    core::{Atom, QuotedChar},
+    flag::{Flag, FlagNameAttribute, FlagPerm},
     mailbox::Mailbox,
     response::{Code, Data, Response, Status},
+    status::{StatusDataItem, StatusDataItemName},
 };
-use serde_json::Value;
-use std::collections::HashMap;
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap};
+use std::num::NonZeroU32;
+use std::str;
+
+use crate::api::{Folder, MessageSummary};
+use crate::config;
+use crate::uid::UidMap;
+
+// Les classeurs créés par l'utilisateur, d'après la réponse de l'API pour un dossier
+pub fn classeurs(folder: &Folder) -> Vec<(String, u32)> {
+    folder
+        .classeurs
+        .iter()
+        .map(|classeur| (classeur.libelle.clone(), classeur.id))
+        .collect()
+}
+
+// Les dossiers d'EcoleDirecte, qui ne peuvent être ni créés, ni renommés,
+// ni supprimés. Leur rôle ne dépend pas du nom sous lequel on les affiche.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Role {
+    Inbox,
+    Sent,
+    Archive,
+    Drafts,
+}
+
+impl Role {
+    pub const ALL: [Role; 4] = [Role::Inbox, Role::Sent, Role::Archive, Role::Drafts];
+
+    // Nom affiché, traduisible dans la configuration
+    pub fn name(self) -> &'static str {
+        let folders = &config::get().folders;
+        match self {
+            Role::Inbox => "INBOX",
+            Role::Sent => &folders.sent,
+            Role::Archive => &folders.archive,
+            Role::Drafts => &folders.drafts,
+        }
+    }
+
+    // Nom sous lequel on conserve les données du dossier (voir `key`), qui ne
+    // doit pas changer avec le nom affiché
+    fn key(self) -> &'static str {
+        match self {
+            Role::Inbox => "INBOX",
+            Role::Sent => "Sent",
+            Role::Archive => "Archived",
+            Role::Drafts => "Drafts",
+        }
+    }
+
+    // Attribut SPECIAL-USE (RFC 6154)
+    pub fn attribute(self) -> Option<FlagNameAttribute<'static>> {
+        let attribute = match self {
+            Role::Inbox => return None,
+            Role::Sent => "Sent",
+            Role::Archive => "Archive",
+            Role::Drafts => "Drafts",
+        };
+        // unwrap: ce sont des atomes valides
+        Some(FlagNameAttribute::from(Atom::try_from(attribute).unwrap()))
+    }
+}
+
+pub fn role(mailbox: &str) -> Option<Role> {
+    if mailbox.eq_ignore_ascii_case("INBOX") {
+        return Some(Role::Inbox);
+    }
+    Role::ALL.into_iter().find(|role| role.name() == mailbox)
+}
+
+pub fn is_system(mailbox: &str) -> bool {
+    role(mailbox).is_some()
+}
 
 pub fn make_folders(folders: Vec<(String, u32)>) -> HashMap<String, u32> {
     let mut map: HashMap<_, _> = folders.into_iter().collect();
-    map.insert("INBOX".into(), 0);
-    map.insert("Sent".into(), 0);
-    map.insert("Archived".into(), 0);
-    map.insert("Drafts".into(), 0);
+    for role in Role::ALL {
+        map.insert(role.name().into(), 0);
+    }
     map
 }
 
+// Le type de messages que l'API renvoie pour un dossier
+pub fn kind(mailbox: &str) -> &'static str {
+    match role(mailbox) {
+        Some(Role::Sent) => "sent",
+        Some(Role::Archive) => "archived",
+        Some(Role::Drafts) => "draft",
+        _ => "received",
+    }
+}
+
+// Point de vue depuis lequel l'API renvoie le contenu d'un message
+pub fn mode(mailbox: &str) -> &'static str {
+    match role(mailbox) {
+        Some(Role::Sent | Role::Drafts) => "expediteur",
+        _ => "destinataire",
+    }
+}
+
+// Les noms de dossiers sont en UTF-7 modifié (RFC 3501, 5.1.3) : les caractères
+// ASCII imprimables sont tels quels ("&" devient "&-") et les autres sont en
+// UTF-16 encodé en base64 (avec "," au lieu de "/") entre "&" et "-"
+pub fn encode(name: &str) -> String {
+    fn flush(pending: &mut Vec<u16>, encoded: &mut String) {
+        if pending.is_empty() {
+            return;
+        }
+        let bytes: Vec<u8> = pending.drain(..).flat_map(u16::to_be_bytes).collect();
+        encoded.push('&');
+        encoded.push_str(&base64.encode(bytes).replace('/', ","));
+        encoded.push('-');
+    }
+
+    let mut encoded = String::new();
+    let mut pending = vec![];
+    for c in name.chars() {
+        match c {
+            ' '..='~' => {
+                flush(&mut pending, &mut encoded);
+                match c {
+                    '&' => encoded.push_str("&-"),
+                    c => encoded.push(c),
+                }
+            }
+            c => pending.extend_from_slice(c.encode_utf16(&mut [0; 2])),
+        }
+    }
+    flush(&mut pending, &mut encoded);
+    encoded
+}
+
+// Le contraire de `encode`, ou rien si le nom n'est pas en UTF-7 modifié
+pub fn decode(name: &[u8]) -> Option<Cow<'_, str>> {
+    if !name.contains(&b'&') {
+        return match name.iter().all(|byte| (b' '..=b'~').contains(byte)) {
+            // unwrap: de l'ASCII
+            true => Some(Cow::Borrowed(str::from_utf8(name).unwrap())),
+            false => None,
+        };
+    }
+
+    let mut decoded = String::new();
+    let mut rest = name;
+    while let Some((&byte, tail)) = rest.split_first() {
+        match byte {
+            b'&' => {
+                let end = tail.iter().position(|&byte| byte == b'-')?;
+                let encoded: Vec<u8> = tail[..end]
+                    .iter()
+                    .map(|&byte| if byte == b',' { b'/' } else { byte })
+                    .collect();
+                if encoded.is_empty() {
+                    decoded.push('&');
+                } else {
+                    let bytes = base64.decode(encoded).ok()?;
+                    if bytes.len() % 2 != 0 {
+                        return None;
+                    }
+                    let units: Vec<u16> = bytes
+                        .chunks(2)
+                        .map(|unit| u16::from_be_bytes([unit[0], unit[1]]))
+                        .collect();
+                    decoded.push_str(&String::from_utf16(&units).ok()?);
+                }
+                rest = &tail[end + 1..];
+            }
+            b' '..=b'~' => {
+                decoded.push(byte as char);
+                rest = tail;
+            }
+            _ => return None,
+        }
+    }
+    Some(Cow::Owned(decoded))
+}
+
+// Le nom du dossier tel qu'on le connaît, décodé (voir `encode`)
+// unwrap: les noms des commandes sont vérifiés par `valid_names`
+pub fn name<'a>(mailbox: &'a Mailbox<'_>) -> Cow<'a, str> {
+    match mailbox {
+        Mailbox::Inbox => Cow::Borrowed("INBOX"),
+        Mailbox::Other(mailbox) => decode(mailbox.as_ref()).unwrap(),
+    }
+}
+
+// Un littéral peut contenir n'importe quel octet : les noms de dossiers de la
+// commande doivent être en UTF-7 modifié avant qu'on appelle `name`
+pub fn valid_names(body: &CommandBody<'_>) -> bool {
+    use CommandBody::*;
+    let mailboxes = match body {
+        Select { mailbox }
+        | Examine { mailbox }
+        | Create { mailbox }
+        | Delete { mailbox }
+        | Subscribe { mailbox }
+        | Unsubscribe { mailbox }
+        | Status { mailbox, .. }
+        | Append { mailbox, .. }
+        | Copy { mailbox, .. }
+        | Move { mailbox, .. } => vec![mailbox],
+        Rename { from, to } => vec![from, to],
+        List { reference, .. } | Lsub { reference, .. } => vec![reference],
+        _ => vec![],
+    };
+    mailboxes.into_iter().all(|mailbox| match mailbox {
+        Mailbox::Inbox => true,
+        Mailbox::Other(mailbox) => decode(mailbox.as_ref()).is_some(),
+    })
+}
+
+// Les messages dans l'ordre des identifiants EcoleDirecte (l'API les renvoie
+// du plus récent au plus ancien), qui est celui dans lequel on attribue les UIDs
+pub fn messages(mailbox: &str, folder: &mut Folder) -> Vec<MessageSummary> {
+    let messages = &mut folder.messages;
+    let mut messages = std::mem::take(match role(mailbox) {
+        Some(Role::Sent) => &mut messages.sent,
+        Some(Role::Archive) => &mut messages.archived,
+        Some(Role::Drafts) => &mut messages.draft,
+        _ => &mut messages.received,
+    });
+    messages.sort_by_key(|message| message.id);
+    messages
+}
+
+// Action de l'API qui déplace un message d'un dossier à l'autre. Seuls les
+// messages reçus se déplacent, entre INBOX, Archived et les classeurs.
+pub fn move_action(from: &str, to: &str) -> Option<&'static str> {
+    use Role::*;
+    match (role(from), role(to)) {
+        _ if from == to => None,
+        (Some(Sent | Drafts), _) | (_, Some(Sent | Drafts)) => None,
+        (_, Some(Archive)) => Some("archiver"),
+        (Some(Archive), Some(Inbox)) => Some("desarchiver"),
+        // Vers l'INBOX (classeur 0) ou un classeur
+        _ => Some("deplacer"),
+    }
+}
+
+// Nom de fichier sous lequel on conserve les données d'un dossier : les
+// classeurs gardent leur identifiant même quand ils sont renommés
+pub fn key(mailbox: &str, id: u32) -> String {
+    match id {
+        0 => role(mailbox).map_or(mailbox.to_string(), |role| role.key().to_string()),
+        id => format!("classeur-{}", id),
+    }
+}
+
+fn bounds(sequence: &Sequence, largest: u32) -> (u32, u32) {
+    let value = |seq: &SeqOrUid| match seq {
+        SeqOrUid::Value(value) => value.get(),
+        SeqOrUid::Asterisk => largest,
+    };
+    match sequence {
+        Sequence::Single(seq) => (value(seq), value(seq)),
+        Sequence::Range(from, to) => {
+            let (from, to) = (value(from), value(to));
+            (from.min(to), from.max(to))
+        }
+    }
+}
+
+// Vrai si le numéro (de séquence ou UID) est dans l'ensemble
+pub fn contains(sequence_set: &SequenceSet, number: u32, largest: u32) -> bool {
+    sequence_set.0.as_ref().iter().any(|sequence| {
+        let (from, to) = bounds(sequence, largest);
+        (from..=to).contains(&number)
+    })
+}
+
+// Renvoie les indices des messages (triés par UID) dont l'UID est dans l'ensemble
+pub fn expand_uids(sequence_set: &SequenceSet, uids: &[NonZeroU32]) -> Vec<usize> {
+    let largest = uids.last().map_or(0, |uid| uid.get());
+    let ranges: Vec<_> = sequence_set
+        .0
+        .as_ref()
+        .iter()
+        .map(|sequence| bounds(sequence, largest))
+        .collect();
+
+    uids.iter()
+        .enumerate()
+        .filter(|(_, uid)| {
+            ranges
+                .iter()
+                .any(|(from, to)| (*from..=*to).contains(&uid.get()))
+        })
+        .map(|(index, _)| index)
+        .collect()
+}
+
+// Renvoie les indices (à partir de 0) désignés par l'ensemble de séquence,
+// dans l'ordre croissant et sans doublons
+pub fn expand(sequence_set: &SequenceSet, largest: u32) -> Vec<usize> {
+    let mut numbers: Vec<u32> = sequence_set
+        .0
+        .as_ref()
+        .iter()
+        .flat_map(|sequence| {
+            let (from, to) = bounds(sequence, largest);
+            from..=to.min(largest)
+        })
+        .filter(|&number| number >= 1 && number <= largest)
+        .collect();
+    numbers.sort_unstable();
+    numbers.dedup();
+    numbers
+        .into_iter()
+        .map(|number| number as usize - 1)
+        .collect()
+}
+
+// Séparateur de hiérarchie : un classeur "Cours/Maths" apparaît comme un
+// sous-dossier de "Cours"
+pub const DELIMITER: char = '/';
+
+pub fn delimiter() -> Option<QuotedChar> {
+    QuotedChar::try_from(DELIMITER).ok()
+}
+
+// Correspondance avec un motif de LIST (RFC 3501 6.3.8) : "*" remplace
+// n'importe quoi, "%" n'importe quoi sauf le séparateur de hiérarchie.
+// `matched[end]` dit si le début du motif déjà lu correspond à `name[..end]` :
+// un passage par caractère du motif, sans retour en arrière, pour qu'une suite
+// de jokers ne coûte pas un temps exponentiel.
+fn wildcard(pattern: &[u8], name: &[u8]) -> bool {
+    let mut matched = vec![false; name.len() + 1];
+    matched[0] = true;
+    for &c in pattern {
+        let mut next = vec![false; name.len() + 1];
+        let mut any = false;
+        for end in 0..=name.len() {
+            next[end] = match c {
+                b'*' => {
+                    any |= matched[end];
+                    any
+                }
+                b'%' => {
+                    // Le joker ne peut pas couvrir le séparateur
+                    if end > 0 && name[end - 1] == DELIMITER as u8 {
+                        any = false;
+                    }
+                    any |= matched[end];
+                    any
+                }
+                c => end > 0 && matched[end - 1] && name[end - 1] == c,
+            };
+        }
+        matched = next;
+    }
+    matched[name.len()]
+}
+
+fn matches(pattern: &[u8], name: &str) -> bool {
+    // INBOX ne tient pas compte de la casse
+    if name == "INBOX" && pattern.len() >= 5 && pattern[..5].eq_ignore_ascii_case(b"INBOX") {
+        return wildcard(&[b"INBOX", &pattern[5..]].concat(), name.as_bytes());
+    }
+    wildcard(pattern, name.as_bytes())
+}
+
+// imap-codec ne connaît pas les options de LIST-EXTENDED (RFC 5258), comme
+// LIST (SPECIAL-USE) "" "*" ou LIST "" "*" RETURN (SPECIAL-USE) : on les retire
+// de la ligne de commande (sans CRLF) avant de la décoder. Renvoie la ligne
+// réécrite et vrai si seuls les dossiers SPECIAL-USE sont demandés. Les autres
+// options (SUBSCRIBED, REMOTE...) sont ignorées.
+pub fn list_extended(line: &[u8]) -> Option<(Vec<u8>, bool)> {
+    let tag_end = line.iter().position(|&c| c == b' ')?;
+    let (tag, rest) = (&line[..tag_end], &line[tag_end + 1..]);
+    if rest.len() < 5 || !rest[..5].eq_ignore_ascii_case(b"LIST ") {
+        return None;
+    }
+    let mut arguments = &rest[5..];
+
+    let mut selection = None;
+    if arguments.starts_with(b"(") {
+        let end = arguments.iter().position(|&c| c == b')')?;
+        selection = Some(&arguments[1..end]);
+        arguments = arguments[end + 1..].strip_prefix(b" ")?;
+    }
+
+    let mut returned = false;
+    let upper = arguments.to_ascii_uppercase();
+    if arguments.ends_with(b")") {
+        if let Some(position) = upper.windows(9).rposition(|window| window == b" RETURN (") {
+            arguments = &arguments[..position];
+            returned = true;
+        }
+    }
+
+    if selection.is_none() && !returned {
+        return None;
+    }
+    let special_use = selection
+        .unwrap_or_default()
+        .split(|&c| c == b' ')
+        .any(|option| option.eq_ignore_ascii_case(b"SPECIAL-USE"));
+    Some(([tag, b" LIST ", arguments].concat(), special_use))
+}
+
+// Réponse à LIST (ou LSUB) : les dossiers qui correspondent au motif, ainsi
+// que leurs parents qui n'existent pas (avec \Noselect). Avec `special_use`,
+// seulement les dossiers qui ont un attribut SPECIAL-USE.
 pub fn filter<'a>(
-    folders: &'a HashMap<String, u32>,
-    reference: Mailbox<'_>,
+    names: impl IntoIterator<Item = &'a str>,
+    reference: &Mailbox<'_>,
     mailbox_wildcard: &[u8],
-) -> Vec<Response<'a>> {
-    folders // TODO!!!
-        .keys()
-        .map(|folder| {
-            Response::Data(Data::List {
-                items: vec![],
-                delimiter: None,
-                mailbox: <Mailbox as TryFrom<&str>>::try_from(folder).unwrap(),
-            })
+    lsub: bool,
+    special_use: bool,
+) -> Vec<Response<'static>> {
+    // Dossier -> sélectionnable
+    let mut folders = BTreeMap::new();
+    for name in names {
+        folders.insert(name.to_string(), true);
+        let mut parent = name;
+        while let Some((prefix, _)) = parent.rsplit_once(DELIMITER) {
+            folders.entry(prefix.to_string()).or_insert(false);
+            parent = prefix;
+        }
+    }
+
+    // Le motif est en UTF-7 modifié, comme les noms envoyés au client
+    let pattern = [encode(&self::name(reference)).as_bytes(), mailbox_wildcard].concat();
+    let mut matching: Vec<_> = folders
+        .into_iter()
+        .filter(|(name, _)| !name.is_empty() && matches(&pattern, &encode(name)))
+        .filter(|(name, _)| !special_use || role(name).and_then(Role::attribute).is_some())
+        .collect();
+    // L'INBOX en premier, puis l'ordre alphabétique
+    matching.sort_by_key(|(name, _)| name != "INBOX");
+
+    matching
+        .into_iter()
+        .filter_map(|(name, selectable)| {
+            let mut items = match selectable {
+                true => vec![],
+                false => vec![FlagNameAttribute::Noselect],
+            };
+            items.extend(role(&name).and_then(Role::attribute));
+            let mailbox = Mailbox::try_from(encode(&name)).ok()?;
+            Some(Response::Data(match lsub {
+                true => Data::Lsub {
+                    items,
+                    delimiter: delimiter(),
+                    mailbox,
+                },
+                false => Data::List {
+                    items,
+                    delimiter: delimiter(),
+                    mailbox,
+                },
+            }))
         })
         .collect()
 }
 
-pub fn mailbox_info<'a, 'b>(mailbox: &'a str, folder: Value) -> Vec<Response<'b>> {
-    let existing_messages_count = match mailbox {
-        "Sent" => &folder["pagination"]["messagesEnvoyesCount"],
-        "Archived" => &folder["pagination"]["messagesArchivesCount"],
-        "Drafts" => &folder["pagination"]["messagesDraftCount"],
-        _ => &folder["pagination"]["messagesRecusCount"],
+// Ensemble d'UIDs au format IMAP (1:3,7), dans l'ordre donné
+pub fn uid_set(uids: &[NonZeroU32]) -> String {
+    let mut ranges: Vec<(u32, u32)> = vec![];
+    for uid in uids.iter().map(|uid| uid.get()) {
+        match ranges.last_mut() {
+            Some((_, last)) if *last + 1 == uid => *last = uid,
+            _ => ranges.push((uid, uid)),
+        }
     }
-    .as_u64()
-    .unwrap() as u32;
+    ranges
+        .into_iter()
+        .map(|(first, last)| match first == last {
+            true => first.to_string(),
+            false => format!("{}:{}", first, last),
+        })
+        .collect::<Vec<_>>()
+        .join(",")
+}
 
-    let unseen_messages_count = match mailbox {
-        "Sent" => None,
-        "Archived" => None,
-        "Drafts" => None,
-        _ => folder["pagination"]["messagesRecusNotReadCount"].as_u64(),
-    };
+// Réponse à STATUS. Les compteurs viennent de la liste des messages, comme les
+// numéros de séquence, plutôt que de ceux de l'API.
+pub fn status(
+    messages: &[MessageSummary],
+    uids: &UidMap,
+    names: &[StatusDataItemName],
+) -> Vec<StatusDataItem> {
+    names
+        .iter()
+        .map(|name| match name {
+            StatusDataItemName::Messages => StatusDataItem::Messages(messages.len() as u32),
+            StatusDataItemName::Recent => StatusDataItem::Recent(0),
+            StatusDataItemName::UidNext => StatusDataItem::UidNext(uids.next),
+            StatusDataItemName::UidValidity => StatusDataItem::UidValidity(uids.validity),
+            StatusDataItemName::Unseen => StatusDataItem::Unseen(
+                messages.iter().filter(|message| !message.read).count() as u32,
+            ),
+            // Aucun message n'a \Deleted (voir `message::storable`)
+            StatusDataItemName::Deleted => StatusDataItem::Deleted(0),
+            StatusDataItemName::DeletedStorage => StatusDataItem::DeletedStorage(0),
+        })
+        .collect()
+}
 
-    let date = Local::now().date_naive();
-    // unwrap: Normalement on est après l'an 0
-    let school_year: u32 = match date.month() {
-        1..=8 => date.year() - 1,
-        9..=12 => date.year(),
-        _ => panic!("Month must be in the 1..=12 range"),
-    }
-    .try_into()
-    .unwrap();
+// En lecture seule (EXAMINE), aucun drapeau ne peut être modifié. `messages`
+// est dans l'ordre des numéros de séquence.
+pub fn mailbox_info<'b>(
+    messages: &[MessageSummary],
+    uids: &UidMap,
+    read_only: bool,
+) -> Vec<Response<'b>> {
+    // \Seen est conservé par EcoleDirecte, le reste localement
+    let permanent_flags = match read_only {
+        true => vec![],
+        false => vec![
+            FlagPerm::Flag(Flag::Seen),
+            FlagPerm::Flag(Flag::Answered),
+            FlagPerm::Flag(Flag::Flagged),
+            FlagPerm::Flag(Flag::Draft),
+            FlagPerm::Asterisk,
+        ],
+    };
 
     let mut response = vec![
-        Response::Data(Data::Flags(vec![Flag::Seen, Flag::Answered])),
-        Response::Data(Data::Exists(existing_messages_count)),
+        Response::Data(Data::Flags(vec![
+            Flag::Seen,
+            Flag::Answered,
+            Flag::Flagged,
+            Flag::Draft,
+        ])),
+        Response::Data(Data::Exists(messages.len() as u32)),
         Response::Data(Data::Recent(0)),
         Response::Status(
-            Status::ok(
-                None,
-                Some(Code::PermanentFlags(vec![FlagPerm::Flag(Flag::Seen)])),
-                "Flags",
-            )
-            .unwrap(),
+            Status::ok(None, Some(Code::PermanentFlags(permanent_flags)), "Flags").unwrap(),
         ),
         Response::Status(
-            Status::ok(
-                None,
-                Some(Code::UidValidity(school_year.try_into().unwrap())),
-                format!("Valide en {}-{}", school_year, school_year + 1),
-            )
-            .unwrap(),
+            Status::ok(None, Some(Code::UidValidity(uids.validity)), "UIDs valid").unwrap(),
+        ),
+        Response::Status(
+            Status::ok(None, Some(Code::UidNext(uids.next)), "Predicted next UID").unwrap(),
         ),
     ];
 
-    if let Some(count) = unseen_messages_count {
-        let count = count as u32;
-        if let Ok(count) = count.try_into() {
-            response.push(Response::Status(
-                Status::ok(None, Some(Code::Unseen(count)), "Unseen").unwrap(),
-            ));
-        }
+    // UNSEEN est le numéro du premier message non lu, absent s'ils sont tous lus
+    if let Some(index) = messages.iter().position(|message| !message.read) {
+        // unwrap: index + 1 > 0
+        let number = NonZeroU32::new(index as u32 + 1).unwrap();
+        response.push(Response::Status(
+            Status::ok(None, Some(Code::Unseen(number)), "First unseen").unwrap(),
+        ));
     }
 
     response
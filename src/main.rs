@@ -1,463 +1,276 @@
-use imap_codec::{
-    decode::{AuthenticateDataDecodeError, CommandDecodeError, Decoder},
-    encode::Encoder,
-    imap_types::{
-        self,
-        auth::AuthMechanism,
-        bounded_static::IntoBoundedStatic,
-        command::Command,
-        core::Text,
-        mailbox::{ListMailbox, Mailbox},
-        response::{
-            Code, CommandContinuationRequest, Data, Greeting, GreetingKind, Response, Status,
-        },
-        secret::Secret,
-        state::State,
-    },
-    AuthenticateDataCodec, CommandCodec, GreetingCodec, ResponseCodec,
-};
-use std::borrow::Cow;
-use std::collections::HashMap;
-use std::io::{Read, Write};
-use std::net::{TcpListener, TcpStream};
-use std::ops::Range;
-use std::str;
-use std::thread;
+use clap::Parser;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::sync::Arc;
+use tokio::net::TcpListener;
+use tokio::signal::unix::{signal, SignalKind};
 
 use ecoledirecte_imap::api;
-use ecoledirecte_imap::auth;
-use ecoledirecte_imap::capabilities;
-use ecoledirecte_imap::mailbox;
-
-struct Connection<'a> {
-    state: State<'a>,
-    user: Option<auth::User>,
-    folders: Option<HashMap<String, u32>>,
+use ecoledirecte_imap::api_settings;
+use ecoledirecte_imap::backend::EcoleDirecte;
+use ecoledirecte_imap::config::{self, Config};
+use ecoledirecte_imap::server::{responder, Connection};
+use ecoledirecte_imap::tls::{Stream, Tls};
+
+// Les options ont la priorité sur les variables d'environnement, qui l'ont
+// sur le fichier de configuration
+#[derive(Parser)]
+#[command(version, about = "Serveur IMAP pour la messagerie d'EcoleDirecte")]
+struct Args {
+    // Par défaut $XDG_CONFIG_HOME/ecoledirecte-imap/config.toml, s'il existe
+    #[arg(short, long, value_name = "FICHIER", help = "Fichier de configuration")]
+    config: Option<PathBuf>,
+    #[arg(
+        short,
+        long,
+        value_name = "ADRESSE",
+        help = "Adresse IPv4 ou IPv6 (répétable)"
+    )]
+    listen: Vec<String>,
+    #[arg(short, long, help = "Port d'écoute")]
+    port: Option<u16>,
+    #[arg(long, value_name = "DOSSIER", help = "Dossier des données en cache")]
+    data_dir: Option<PathBuf>,
+    #[arg(
+        long,
+        value_name = "SECONDES",
+        help = "Intervalle entre deux interrogations de l'API pendant IDLE"
+    )]
+    idle_interval: Option<u64>,
+    #[arg(
+        long,
+        value_name = "SECONDES",
+        help = "Durée de vie du cache des listes de messages"
+    )]
+    cache_max_age: Option<u64>,
+    #[arg(long, value_name = "NOM", help = "Nom du dossier des messages envoyés")]
+    sent_folder: Option<String>,
+    #[arg(
+        long,
+        value_name = "NOM",
+        help = "Nom du dossier des messages archivés"
+    )]
+    archive_folder: Option<String>,
+    #[arg(long, value_name = "NOM", help = "Nom du dossier des brouillons")]
+    drafts_folder: Option<String>,
+    #[arg(
+        long,
+        value_name = "NIVEAU",
+        help = "off, error, warn, info, debug ou trace"
+    )]
+    log_level: Option<String>,
+    #[arg(long, help = "Vérifie la configuration puis quitte")]
+    check_config: bool,
 }
 
-impl<'a> Default for Connection<'a> {
-    fn default() -> Connection<'a> {
-        Connection {
-            state: State::Greeting,
-            user: None,
-            folders: None,
-        }
+fn load(args: &Args) -> Result<Config, String> {
+    let mut config = match &args.config {
+        Some(path) => Config::load(path)?,
+        None => match config::default_path().filter(|path| path.exists()) {
+            Some(path) => Config::load(&path)?,
+            None => Config::default(),
+        },
+    };
+    config.apply_env()?;
+    if !args.listen.is_empty() {
+        config.server.listen = args.listen.clone();
     }
-}
-
-fn main() {
-    let listener = TcpListener::bind("localhost:1993").unwrap();
-    let client = reqwest::blocking::Client::new();
-
-    thread::scope(|s| {
-        for stream in listener.incoming() {
-            let stream = stream.unwrap();
-
-            s.spawn(|| responder(stream, Connection::default(), &client));
-        }
-    });
-}
-
-trait AsRange {
-    fn as_range_of(&self, other: &Self) -> Option<Range<usize>>;
-}
-
-impl<T> AsRange for [T] {
-    fn as_range_of(&self, other: &[T]) -> Option<Range<usize>> {
-        let self_ = self.as_ptr_range();
-        let other = other.as_ptr_range();
-        if other.start > self_.start || self_.end > other.end {
-            None
-        } else {
-            let from = unsafe { self_.start.offset_from(other.start) };
-            let to = unsafe { self_.end.offset_from(other.start) };
-            Some((from as usize)..(to as usize))
-        }
+    if let Some(port) = args.port {
+        config.server.port = port;
     }
-}
-
-fn responder(
-    mut stream: TcpStream,
-    mut connection: Connection<'_>,
-    client: &reqwest::blocking::Client,
-) {
-    let mut buffer = [0u8; 1024];
-    let mut cursor = 0;
-
-    stream
-        .write(
-            &GreetingCodec::default()
-                .encode(&Greeting {
-                    kind: GreetingKind::Ok,
-                    code: Some(Code::Capability(capabilities())),
-                    text: Text::try_from("ecoledirecte-imap ready").unwrap(),
-                })
-                .dump(),
-        )
-        .unwrap();
-
-    connection.state = State::NotAuthenticated;
-
-    loop {
-        match CommandCodec::default().decode(&buffer[..cursor]) {
-            Ok((remaining, command)) => {
-                print!(
-                    "C: {}",
-                    str::from_utf8(&CommandCodec::default().encode(&command).dump()).unwrap()
-                );
-                for response in process(command, &mut connection, &mut stream, client) {
-                    print!(
-                        "S: {}",
-                        str::from_utf8(&ResponseCodec::default().encode(&response).dump()).unwrap()
-                    );
-                    stream
-                        .write(&ResponseCodec::default().encode(&response).dump())
-                        .unwrap();
-                }
-
-                if let State::Logout = connection.state {
-                    break;
-                }
-
-                let range = remaining.as_range_of(&buffer).unwrap();
-                cursor = range.len();
-                buffer.copy_within(range, 0);
-            }
-            Err(CommandDecodeError::LiteralFound { tag, length, mode }) => {
-                todo!("LITERAL {:?} {} {:?}", tag, length, mode)
-            }
-            Err(CommandDecodeError::Incomplete) => {
-                if cursor >= buffer.len() {
-                    todo!("OUT OF MEMORY!");
-                }
-                let received = stream.read(&mut buffer[cursor..]).unwrap();
-                if received == 0 {
-                    break;
-                }
-                cursor += received;
-            }
-            Err(CommandDecodeError::Failed) => {
-                stream
-                    .write(
-                        &ResponseCodec::default()
-                            .encode(&Response::Status(
-                                Status::bad(None, None, "Parsing failed").unwrap(),
-                            ))
-                            .dump(),
-                    )
-                    .unwrap();
-                cursor = 0;
-            }
-        }
+    if let Some(dir) = &args.data_dir {
+        config.storage.data_dir = Some(dir.clone());
+    }
+    if let Some(seconds) = args.idle_interval {
+        config.polling.idle_interval = seconds;
+    }
+    if let Some(seconds) = args.cache_max_age {
+        config.polling.cache_max_age = seconds;
+    }
+    if let Some(name) = &args.sent_folder {
+        config.folders.sent = name.clone();
+    }
+    if let Some(name) = &args.archive_folder {
+        config.folders.archive = name.clone();
+    }
+    if let Some(name) = &args.drafts_folder {
+        config.folders.drafts = name.clone();
+    }
+    if let Some(level) = &args.log_level {
+        config.log.level = level.clone();
     }
+    Ok(config)
 }
 
-fn process<'a>(
-    command: Command<'a>,
-    connection: &'a mut Connection<'_>,
-    stream: &mut TcpStream,
-    client: &reqwest::blocking::Client,
-) -> Vec<Response<'a>> {
-    use imap_types::{
-        command::CommandBody::*,
-        command::CommandBody::{Logout, Status as StatusCommand},
-        response::Status,
-        state::State::{Logout as LogoutState, *},
-    };
-
-    // Déplacement partiel (tag reste possédé par command)
-    match command.body {
-        Capability => {
-            return vec![
-                Response::Data(Data::Capability(capabilities())),
-                Response::Status(
-                    Status::ok(Some(command.tag), None, "CAPABILITY completed").unwrap(),
-                ),
-            ]
+#[tokio::main]
+async fn main() -> ExitCode {
+    let args = Args::parse();
+    let config = match load(&args) {
+        Ok(config) => config,
+        Err(error) => {
+            eprintln!("{}", error);
+            return ExitCode::FAILURE;
         }
-        Noop => {
-            return vec![Response::Status(
-                Status::ok(Some(command.tag), None, "NOOP completed").unwrap(),
-            )]
+    };
+    let mut errors = config.validate();
+    // Les fichiers sont lus dès maintenant pour que --check-config les vérifie
+    let mut tls = None;
+    if let (Some(certificate), Some(key)) = (&config.tls.certificate, &config.tls.key) {
+        match Tls::load(certificate, key) {
+            Ok(loaded) => tls = Some(Arc::new(loaded)),
+            Err(error) => errors.push(error),
         }
-        Logout => {
-            connection.state = LogoutState;
-            return vec![
-                Response::Status(Status::bye(None, "Logging out!").unwrap()),
-                Response::Status(Status::ok(Some(command.tag), None, "LOGOUT completed").unwrap()),
-            ];
+    }
+    for error in &errors {
+        eprintln!("{}", error);
+    }
+    if args.check_config || !errors.is_empty() {
+        if errors.is_empty() {
+            println!("Configuration valide");
+            return ExitCode::SUCCESS;
         }
-        _ => (),
+        return ExitCode::FAILURE;
     }
 
-    if connection.state == NotAuthenticated {
-        match command.body {
-            Authenticate {
-                mechanism,
-                initial_response,
-            } => {
-                if mechanism != AuthMechanism::Plain {
-                    return vec![Response::Status(
-                        Status::no(Some(command.tag), None, "Unsupported mechanism").unwrap(),
-                    )];
-                }
-                if initial_response != None {
-                    return vec![Response::Status(
-                        Status::no(Some(command.tag), None, "Unexpected initial response").unwrap(),
-                    )];
-                }
-
-                stream
-                    .write(
-                        &ResponseCodec::default()
-                            .encode(&Response::CommandContinuationRequest(
-                                CommandContinuationRequest::Base64(Cow::Borrowed(&[])),
-                            ))
-                            .dump(),
-                    )
-                    .unwrap();
-
-                // TODO: Malgré cette tentative de faire marcher les choses (voir commentaire suivant),
-                // ça va pas :
-                // Si le client envoie un paquet AAABBB avec AAA une commande AUTHENTICATE PLAIN
-                // normalement on devrait lire BBB pour avoir les données d'authentification
-                // mais ici on n'y a pas accès (elles sont dans le buffer de la boucle principale)
-                // donc on se retrouvera à lire CCC d'un autre paquet
-                // Donc la gestion totale pour AAABBB, CCC... serait AAA, CCC, BBB, ...
-                // ce qui ne va clairement pas (même si en pratique si le client est bien discipliné
-                // il devrait attendre de recevoir la confirmation du serveur pour envoyer les données
-                // d'authentification. il y a quand même de quoi améliorer les choses + aussi les
-                // littéraux non-synchronisants poseraient problème (mais je sais pas s'ils peuvent
-                // être utilisés pendant l'authentification))
-                let mut buffer = [0u8; 1024];
-                let mut consumed = 0;
-                let mut peeked;
-
-                peeked = stream.peek(&mut buffer).unwrap();
-
-                // Le problème est de consommer juste la bonne quantité de données
-                // pour que le reste soit géré par la boucle principale
-                // On pourrait utiliser juste peek puis consommer ce qui a été con-
-                // sommé par le codec mais le problème est que peek ne bloque pas
-                // et donc on attendrait en boucle quand il manque des données.
-                // La solution: peek pour obtenir des données (puisque quand il n'y
-                // a pas de données disponibles peek bloque) puis si c'est pas suf-
-                // fisant, on read() les données pour les consommer (puisqu'on sait
-                // qu'on les utilise de toute manière) et on peek à nouveau.
-                let line = loop {
-                    match AuthenticateDataCodec::default().decode(&buffer[..peeked]) {
-                        Ok((remaining, line)) => {
-                            // unwrap: ok puisque remaining est une slice de buffer
-                            let range = remaining.as_range_of(&buffer).unwrap();
-                            // unwrap: ok puisque déjà peeked
-                            stream.read(&mut buffer[consumed..range.start]).unwrap();
-                            break line;
-                        }
-                        Err(AuthenticateDataDecodeError::Incomplete) => {
-                            if peeked >= buffer.len() {
-                                todo!("OUT OF MEMORY");
-                            }
-                            // unwrap: ok puisque déjà peeked
-                            stream.read(&mut buffer[consumed..peeked]).unwrap();
-                            consumed = peeked;
-                            let received = stream.peek(&mut buffer[consumed..]).unwrap();
-                            if received == 0 {
-                                return vec![];
-                            }
-                            peeked += received;
-                        }
-                        Err(AuthenticateDataDecodeError::Failed) => {
-                            stream.read(&mut buffer[consumed..peeked]).unwrap();
-                            return vec![Response::Status(
-                                Status::bad(Some(command.tag), None, "Invalid BASE64 literal")
-                                    .unwrap(),
-                            )];
-                        }
-                    }
-                };
-
-                /* AuthenticateDataCodec ne gère par "*" mais l'erreur failed le gère
-                 * (pour la mauvaise raison :p)
-                 */
-                let (username, password) = match auth::parse_plain_message(
-                    Secret::new(&line.0.declassify()),
-                    command.tag.clone(),
-                ) {
-                    Ok(tup) => tup,
-                    Err(response) => {
-                        return response;
-                    }
-                };
-
-                let (state, user, response) =
-                    auth::translate(api::login(client, username, password), command.tag);
-
-                connection.state = state;
-                connection.user = user;
-                return response;
-            }
-            Login { username, password } => {
-                let (state, user, response) = auth::translate(
-                    api::login(
-                        client,
-                        str::from_utf8(username.as_ref()).unwrap(),
-                        str::from_utf8(password.declassify().as_ref()).unwrap(),
-                    ),
-                    command.tag,
+    // RUST_LOG a la priorité sur la configuration
+    // unwrap: le niveau a été vérifié par `validate`
+    env_logger::Builder::new()
+        .filter_level(config.log_level().unwrap())
+        .parse_default_env()
+        .init();
+
+    // Le port IMAPS, s'il y en a un, est chiffré dès la connexion
+    let mut ports = vec![(config.server.port, false)];
+    ports.extend(config.tls.port.map(|port| (port, true)));
+    // unwrap: les adresses ont été vérifiées par `validate`
+    let addresses: Vec<_> = config
+        .server
+        .listen
+        .iter()
+        .flat_map(|address| {
+            let ip = address.parse::<IpAddr>().unwrap();
+            ports
+                .iter()
+                .map(move |&(port, implicit)| (SocketAddr::new(ip, port), implicit))
+        })
+        .collect();
+    config::set(config);
+
+    let mut listeners = vec![];
+    for (address, implicit) in addresses {
+        match TcpListener::bind(address).await {
+            Ok(listener) => {
+                log::info!(
+                    "En écoute sur {}{}",
+                    address,
+                    if implicit { " (TLS)" } else { "" }
                 );
-
-                connection.state = state;
-                connection.user = user;
-                return response;
+                listeners.push((listener, implicit));
+            }
+            Err(error) => {
+                log::error!("Impossible d'écouter sur {}: {}", address, error);
+                return ExitCode::FAILURE;
             }
-            _ => (),
         }
     }
-
-    if let Authenticated | Selected(_) = connection.state {
-        match command.body {
-            Select { mailbox } => {
-                // unwrap: on est en authenticated ou selected
-                let user = connection.user.as_ref().unwrap();
-                if let None = connection.folders {
-                    let folders =
-                        mailbox::make_folders(api::get_folders(client, user.id, &user.token));
-                    connection.folders = Some(folders);
+    let backend = Arc::new(EcoleDirecte::new(api::Client::new(api_settings()).await));
+
+    // SIGHUP : on relit le certificat et la clé (après un renouvellement)
+    if let Some(tls) = &tls {
+        let tls = tls.clone();
+        // unwrap: SIGHUP peut toujours être intercepté
+        let mut hangups = signal(SignalKind::hangup()).unwrap();
+        tokio::spawn(async move {
+            while hangups.recv().await.is_some() {
+                match tls.reload() {
+                    Ok(()) => log::info!("Certificat rechargé"),
+                    Err(error) => log::error!("Certificat non rechargé : {}", error),
                 }
-                let folders = connection.folders.as_ref().unwrap();
-
-                let name = match mailbox {
-                    Mailbox::Inbox => "INBOX",
-                    Mailbox::Other(ref mailbox) => str::from_utf8(mailbox.as_ref()).unwrap(),
-                };
-                match folders.get(name) {
-                    Some(&id) => {
-                        let mut response = mailbox::mailbox_info(
-                            name,
-                            api::get_folder_info(client, id, user.id, &user.token),
-                        );
-                        response.push(Response::Status(
-                            Status::ok(
-                                Some(command.tag),
-                                Some(Code::ReadWrite),
-                                "SELECT completed",
-                            )
-                            .unwrap(),
-                        ));
+            }
+        });
+    }
 
-                        connection.state = State::Selected(mailbox.into_static());
-                        return response;
-                    }
-                    None => {
-                        let folders =
-                            mailbox::make_folders(api::get_folders(client, user.id, &user.token));
-                        connection.folders = Some(folders);
-                        let folders = connection.folders.as_ref().unwrap();
-                        if folders.contains_key(name) {
-                            return process(
-                                Command {
-                                    tag: command.tag,
-                                    body: Select { mailbox },
-                                },
-                                connection,
-                                stream,
-                                client,
-                            );
-                        } else {
-                            return vec![Response::Status(
-                                Status::no(Some(command.tag), None, "No such mailbox!").unwrap(),
-                            )];
-                        }
+    // Une tâche par adresse d'écoute, puis une tâche par connexion
+    let mut tasks = vec![];
+    for (listener, implicit) in listeners {
+        let backend = backend.clone();
+        let tls = tls.clone();
+        tasks.push(tokio::spawn(async move {
+            loop {
+                let socket = match listener.accept().await {
+                    Ok((socket, _)) => socket,
+                    Err(error) => {
+                        log::warn!("Connexion refusée : {}", error);
+                        continue;
                     }
-                }
-            }
-            Examine { mailbox } => todo!("EXAMINE {:?}", mailbox),
-            Create { mailbox } => todo!("CREATE {:?}", mailbox),
-            Delete { mailbox } => todo!("DELETE {:?}", mailbox),
-            Rename { from, to } => todo!("RENAME {:?} {:?}", from, to),
-            List {
-                reference,
-                mailbox_wildcard,
-            } => {
-                use imap_types::flag::FlagNameAttribute::Noselect;
-                let name = match mailbox_wildcard {
-                    ListMailbox::String(ref name) => name.as_ref(),
-                    ListMailbox::Token(ref name) => name.as_ref(),
                 };
-
-                if name.len() == 0 {
-                    return vec![
-                        Response::Data(Data::List {
-                            items: vec![Noselect],
-                            delimiter: None,
-                            mailbox: Mailbox::try_from("").unwrap(),
-                        }),
-                        Response::Status(
-                            Status::ok(Some(command.tag), None, "LIST completed").unwrap(),
-                        ),
-                    ];
-                }
-
-                // unwrap: on est en authenticated ou selected
-                let user = connection.user.as_ref().unwrap();
-                connection.folders = Some(mailbox::make_folders(api::get_folders(
-                    client,
-                    user.id,
-                    &user.token,
-                )));
-                let folders = connection.folders.as_ref().unwrap();
-
-                let mut response = mailbox::filter(folders, reference, name);
-
-                response.push(Response::Status(
-                    Status::ok(Some(command.tag), None, "LIST completed").unwrap(),
-                ));
-                return response;
+                let backend = backend.clone();
+                let tls = tls.clone();
+                tokio::spawn(async move {
+                    let stream = match (implicit, &tls) {
+                        (true, Some(tls)) => match Stream::accept(socket, tls).await {
+                            Ok(stream) => stream,
+                            Err(error) => {
+                                log::warn!("Échec de la négociation TLS : {}", error);
+                                return;
+                            }
+                        },
+                        _ => Stream::from(socket),
+                    };
+                    responder(stream, Connection::new(tls), backend.as_ref()).await
+                });
             }
-            StatusCommand {
-                mailbox,
-                item_names,
-            } => todo!("STATUS {:?} {:?}", mailbox, item_names),
-            _ => (),
-        }
+        }));
     }
-
-    if let Selected(mailbox) = &connection.state {
-        match command.body {
-            Check => todo!("CHECK ({:?})", mailbox),
-            Close => {
-                connection.state = State::Authenticated;
-                return vec![Response::Status(
-                    Status::ok(Some(command.tag), None, "Mailbox closed").unwrap(),
-                )];
-            }
-            Search {
-                charset,
-                criteria,
-                uid,
-            } => todo!(
-                "SEARCH {:?} {:?} {:?} ({:?})",
-                charset,
-                criteria,
-                uid,
-                mailbox
-            ),
-            Fetch {
-                sequence_set,
-                macro_or_item_names,
-                uid,
-            } => todo!(
-                "FETCH {:?} {:?} {:?} ({:?})",
-                sequence_set,
-                macro_or_item_names,
-                uid,
-                mailbox
-            ),
-            _ => (),
+    for task in tasks {
+        // Les tâches d'écoute ne s'arrêtent pas, sauf en cas de panique
+        if let Err(error) = task.await {
+            log::error!("{}", error);
+            return ExitCode::FAILURE;
         }
     }
+    ExitCode::SUCCESS
+}
 
-    vec![Response::Status(
-        Status::no(Some(command.tag), None, "Not supported!").unwrap(),
-    )]
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn command_line_overrides_environment_and_file() {
+        let path =
+            std::env::temp_dir().join(format!("ecoledirecte-imap-{}.toml", std::process::id()));
+        fs::write(
+            &path,
+            "[storage]\ndata_dir = \"/fichier\"\n\
+             [polling]\nidle_interval = 10\ncache_max_age = 20\n\
+             [folders]\nsent = \"Fichier\"\narchive = \"Archives\"\n",
+        )
+        .unwrap();
+        // Seul test de ce binaire : il peut changer l'environnement
+        std::env::set_var("ECOLEDIRECTE_IMAP_DATA", "/environnement");
+        std::env::set_var("ECOLEDIRECTE_IMAP_IDLE_INTERVAL", "30");
+
+        let args = Args::parse_from([
+            "ecoledirecte-imap",
+            "--config",
+            path.to_str().unwrap(),
+            "--data-dir",
+            "/ligne",
+            "--sent-folder",
+            "Envoyés",
+        ]);
+        let config = load(&args).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        // La ligne de commande, puis l'environnement, puis le fichier
+        assert_eq!(config.storage.data_dir, Some(PathBuf::from("/ligne")));
+        assert_eq!(config.polling.idle_interval, 30);
+        assert_eq!(config.polling.cache_max_age, 20);
+        assert_eq!(config.folders.sent, "Envoyés");
+        assert_eq!(config.folders.archive, "Archives");
+        assert_eq!(config.folders.drafts, "Drafts");
+    }
 }
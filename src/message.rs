@@ -0,0 +1,206 @@
+use crate::api::{MessageSummary, Person};
+use crate::{mime, rfc5322};
+use chrono::{DateTime, FixedOffset, NaiveDateTime, TimeZone};
+use chrono_tz::Europe::Paris;
+use imap_codec::imap_types::{
+    bounded_static::IntoBoundedStatic,
+    core::NString,
+    envelope::{Address, Envelope},
+    fetch::{MessageDataItem, MessageDataItemName},
+    flag::{Flag, FlagFetch, StoreType},
+};
+
+// Les adresses EcoleDirecte n'existent pas vraiment, on en fabrique à partir
+// du rôle et de l'identifiant de la personne.
+pub const HOST: &str = "ecoledirecte.invalid";
+
+// Les dates de l'API sont à l'heure de Paris, quel que soit le fuseau du serveur
+pub fn date(message: &MessageSummary) -> Option<DateTime<FixedOffset>> {
+    let date = NaiveDateTime::parse_from_str(&message.date, "%Y-%m-%d %H:%M:%S").ok()?;
+    Some(Paris.from_local_datetime(&date).earliest()?.fixed_offset())
+}
+
+// \Answered vient d'EcoleDirecte, qui ne permet pas de le retirer : quand le
+// client le fait, on le note localement avec ce marqueur (qui n'est pas un
+// drapeau valide)
+const NOT_ANSWERED: &str = "-\\Answered";
+
+pub fn flags(message: &MessageSummary) -> Vec<Flag<'static>> {
+    let mut flags = vec![];
+    if message.read {
+        flags.push(Flag::Seen);
+    }
+    if message.answered && !message.local_flags.iter().any(|flag| flag == NOT_ANSWERED) {
+        flags.push(Flag::Answered);
+    }
+    let local = message
+        .local_flags
+        .iter()
+        .filter(|flag| *flag != NOT_ANSWERED)
+        .filter_map(|flag| Flag::try_from(flag.as_str()).ok())
+        .map(IntoBoundedStatic::into_static);
+    for flag in local {
+        if !flags.contains(&flag) {
+            flags.push(flag);
+        }
+    }
+    flags
+}
+
+// Vrai pour les drapeaux qu'on peut conserver : \Seen sur EcoleDirecte, les
+// autres localement. \Deleted n'aurait pas de sens sans EXPUNGE.
+pub fn storable(flag: &Flag<'_>) -> bool {
+    matches!(
+        flag,
+        Flag::Seen | Flag::Answered | Flag::Draft | Flag::Flagged | Flag::Keyword(_)
+    )
+}
+
+// Applique un STORE au message : \Seen modifie `read` (à répercuter sur
+// EcoleDirecte), les autres drapeaux vont dans `local_flags`
+pub fn store_flags(message: &mut MessageSummary, kind: &StoreType, flags: &[Flag<'_>]) {
+    let current = self::flags(message);
+    let wanted: Vec<_> = match kind {
+        StoreType::Replace => flags.to_vec(),
+        StoreType::Add => current.iter().chain(flags).cloned().collect(),
+        StoreType::Remove => current
+            .into_iter()
+            .filter(|flag| !flags.contains(flag))
+            .collect(),
+    };
+
+    message.read = wanted.contains(&Flag::Seen);
+    let mut local: Vec<_> = wanted
+        .iter()
+        .filter(|flag| **flag != Flag::Seen)
+        .map(|flag| flag.to_string())
+        .collect();
+    if message.answered && !wanted.contains(&Flag::Answered) {
+        local.push(NOT_ANSWERED.to_string());
+    }
+    local.sort_unstable();
+    local.dedup();
+    message.local_flags = local;
+}
+
+fn nstring(value: String) -> NString<'static> {
+    NString::try_from(value).unwrap_or(NString(None))
+}
+
+pub fn display_name(person: &Person) -> Option<String> {
+    let parts: Vec<_> = [
+        &person.civilite,
+        &person.prenom,
+        &person.particule,
+        &person.nom,
+    ]
+    .into_iter()
+    .filter(|part| !part.is_empty())
+    .map(String::as_str)
+    .collect();
+
+    if parts.is_empty() {
+        Some(person.name.clone()).filter(|name| !name.is_empty())
+    } else {
+        Some(parts.join(" "))
+    }
+}
+
+pub fn local_part(person: &Person) -> String {
+    format!("{}{}", person.role.to_lowercase(), person.id)
+}
+
+fn address(person: &Person) -> Address<'static> {
+    Address {
+        name: display_name(person)
+            .map_or(NString(None), |name| nstring(rfc5322::encode_word(&name))),
+        adl: NString(None),
+        mailbox: nstring(local_part(person)),
+        host: nstring(HOST.to_string()),
+    }
+}
+
+pub fn message_id(message: &MessageSummary) -> String {
+    format!("<{}@{}>", message.id, HOST)
+}
+
+pub fn envelope(message: &MessageSummary) -> Envelope<'static> {
+    let from: Vec<_> = message.from.iter().map(address).collect();
+    let to = message.to.iter().map(address).collect();
+
+    Envelope {
+        date: date(message).map_or(NString(None), |date| nstring(date.to_rfc2822())),
+        subject: nstring(rfc5322::encode_word(&message.subject)),
+        sender: from.clone(),
+        reply_to: from.clone(),
+        from,
+        to,
+        cc: vec![],
+        bcc: vec![],
+        in_reply_to: NString(None),
+        message_id: nstring(message_id(message)),
+    }
+}
+
+// Vrai si l'élément demandé nécessite le message complet (donc un appel à l'API)
+pub fn needs_raw(name: &MessageDataItemName) -> bool {
+    matches!(
+        name,
+        MessageDataItemName::BodyExt { .. }
+            | MessageDataItemName::Body
+            | MessageDataItemName::BodyStructure
+            | MessageDataItemName::Rfc822
+            | MessageDataItemName::Rfc822Header
+            | MessageDataItemName::Rfc822Text
+            | MessageDataItemName::Rfc822Size
+    )
+}
+
+fn literal(data: &[u8]) -> NString<'static> {
+    NString::try_from(data.to_vec()).unwrap_or(NString(None))
+}
+
+pub fn item(
+    message: &MessageSummary,
+    raw: Option<&[u8]>,
+    name: &MessageDataItemName,
+) -> Option<MessageDataItem<'static>> {
+    use imap_codec::imap_types::datetime::DateTime as ImapDateTime;
+
+    match name {
+        MessageDataItemName::Envelope => Some(MessageDataItem::Envelope(envelope(message))),
+        MessageDataItemName::Flags => Some(MessageDataItem::Flags(
+            flags(message).into_iter().map(FlagFetch::Flag).collect(),
+        )),
+        MessageDataItemName::InternalDate => date(message)
+            .and_then(|date| ImapDateTime::try_from(date).ok())
+            .map(MessageDataItem::InternalDate),
+        MessageDataItemName::Rfc822Size => {
+            raw.map(|raw| MessageDataItem::Rfc822Size(raw.len() as u32))
+        }
+        MessageDataItemName::Rfc822 => raw.map(|raw| MessageDataItem::Rfc822(literal(raw))),
+        MessageDataItemName::Rfc822Header => {
+            raw.map(|raw| MessageDataItem::Rfc822Header(literal(rfc5322::header(raw))))
+        }
+        MessageDataItemName::Rfc822Text => {
+            raw.map(|raw| MessageDataItem::Rfc822Text(literal(rfc5322::text(raw))))
+        }
+        MessageDataItemName::Body => {
+            raw.map(|raw| MessageDataItem::Body(mime::parse(raw).body_structure(false)))
+        }
+        MessageDataItemName::BodyStructure => {
+            raw.map(|raw| MessageDataItem::BodyStructure(mime::parse(raw).body_structure(true)))
+        }
+        MessageDataItemName::BodyExt {
+            section, partial, ..
+        } => {
+            let data = rfc5322::section(raw?, section)?;
+            Some(MessageDataItem::BodyExt {
+                section: section.clone().into_static(),
+                origin: partial.map(|(start, _)| start),
+                data: literal(rfc5322::partial(&data, *partial)),
+            })
+        }
+        _ => None,
+    }
+}
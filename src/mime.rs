@@ -0,0 +1,339 @@
+use base64::{engine::general_purpose::STANDARD as base64, Engine};
+use imap_codec::imap_types::{
+    body::{
+        BasicFields, Body, BodyStructure, Disposition, MultiPartExtensionData,
+        SinglePartExtensionData, SpecificFields,
+    },
+    core::{IString, NString, NonEmptyVec},
+};
+use std::num::NonZeroU32;
+
+use crate::api::{File, Message};
+use crate::rfc5322;
+
+// Découpe en lignes de 76 caractères comme le veut la RFC 2045
+fn wrap(encoded: &str) -> Vec<u8> {
+    encoded
+        .as_bytes()
+        .chunks(76)
+        .flat_map(|line| [line, b"\r\n"].concat())
+        .collect()
+}
+
+fn quote(value: &str) -> String {
+    if value.is_ascii() {
+        format!("\"{}\"", value.replace(['\\', '"', ';'], ""))
+    } else {
+        format!("\"{}\"", rfc5322::encode_word(value))
+    }
+}
+
+fn media_type(file_name: &str) -> &'static str {
+    let extension = file_name.rsplit('.').next().unwrap_or("").to_lowercase();
+    match extension.as_str() {
+        "pdf" => "application/pdf",
+        "doc" => "application/msword",
+        "docx" => "application/vnd.openxmlformats-officedocument.wordprocessingml.document",
+        "odt" => "application/vnd.oasis.opendocument.text",
+        "xls" => "application/vnd.ms-excel",
+        "xlsx" => "application/vnd.openxmlformats-officedocument.spreadsheetml.sheet",
+        "ppt" => "application/vnd.ms-powerpoint",
+        "pptx" => "application/vnd.openxmlformats-officedocument.presentationml.presentation",
+        "zip" => "application/zip",
+        "txt" => "text/plain",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "mp3" => "audio/mpeg",
+        "mp4" => "video/mp4",
+        _ => "application/octet-stream",
+    }
+}
+
+fn html(message: &Message) -> Vec<u8> {
+    let content = &message.content;
+    [
+        b"Content-Type: text/html; charset=utf-8\r\n".as_slice(),
+        b"Content-Transfer-Encoding: base64\r\n\r\n",
+        &wrap(&base64.encode(content)),
+    ]
+    .concat()
+}
+
+fn attachment(file: &File, content: &[u8]) -> Vec<u8> {
+    let name = match file.libelle.as_str() {
+        "" => quote("piece-jointe"),
+        libelle => quote(libelle),
+    };
+    let media_type = media_type(&file.libelle);
+    [
+        format!("Content-Type: {}; name={}\r\n", media_type, name).as_bytes(),
+        b"Content-Transfer-Encoding: base64\r\n",
+        format!("Content-Disposition: attachment; filename={}\r\n\r\n", name).as_bytes(),
+        &wrap(&base64.encode(content)),
+    ]
+    .concat()
+}
+
+// Construit le corps MIME (avec ses en-têtes Content-*) à partir du message
+// et des pièces jointes déjà téléchargées (dans l'ordre de `message.files`)
+pub fn build(message: &Message, attachments: &[Vec<u8>]) -> Vec<u8> {
+    let files = &message.files;
+    if files.is_empty() {
+        return html(message);
+    }
+
+    let boundary = format!("=_ecoledirecte-imap-{}", message.summary.id);
+    let mut body = format!(
+        "Content-Type: multipart/mixed; boundary=\"{}\"\r\n\r\n",
+        boundary
+    )
+    .into_bytes();
+
+    let parts = std::iter::once(html(message)).chain(
+        files
+            .iter()
+            .zip(attachments)
+            .map(|(file, content)| attachment(file, content)),
+    );
+    for part in parts {
+        body.extend_from_slice(format!("--{}\r\n", boundary).as_bytes());
+        body.extend_from_slice(&part);
+        body.extend_from_slice(b"\r\n");
+    }
+    body.extend_from_slice(format!("--{}--\r\n", boundary).as_bytes());
+    body
+}
+
+// Une entité MIME d'un message déjà construit (voir RFC 2045)
+pub struct Entity<'a> {
+    pub header: &'a [u8],
+    pub body: &'a [u8],
+    pub parts: Vec<Entity<'a>>,
+}
+
+// Valeur d'un champ d'en-tête, en recollant les lignes repliées
+pub fn field(header: &[u8], name: &str) -> Option<String> {
+    let mut value: Option<String> = None;
+    for line in header.split(|&c| c == b'\n') {
+        let line = String::from_utf8_lossy(line);
+        let line = line.trim_end_matches('\r');
+        if line.starts_with([' ', '\t']) {
+            if let Some(value) = value.as_mut() {
+                value.push_str(line);
+            }
+        } else if value.is_some() {
+            break;
+        } else if let Some((field, rest)) = line.split_once(':') {
+            if field.eq_ignore_ascii_case(name) {
+                value = Some(rest.trim().to_string());
+            }
+        }
+    }
+    value
+}
+
+// Sépare "type/sous-type; param=valeur" en ses composants
+fn parse_parameters(value: &str) -> (String, Vec<(String, String)>) {
+    let mut items = value.split(';');
+    let main = items.next().unwrap_or("").trim().to_string();
+    let parameters = items
+        .filter_map(|item| item.split_once('='))
+        .map(|(name, value)| {
+            (
+                name.trim().to_lowercase(),
+                value.trim().trim_matches('"').to_string(),
+            )
+        })
+        .collect();
+    (main, parameters)
+}
+
+fn split(entity: &[u8]) -> (&[u8], &[u8]) {
+    // Une entité sans en-têtes commence directement par la ligne vide
+    if entity.starts_with(b"\r\n") {
+        return (&entity[..0], &entity[2..]);
+    }
+    match entity.windows(4).position(|window| window == b"\r\n\r\n") {
+        Some(position) => (&entity[..position + 4], &entity[position + 4..]),
+        None => (entity, &entity[entity.len()..]),
+    }
+}
+
+pub fn parse(raw: &[u8]) -> Entity<'_> {
+    let (header, body) = split(raw);
+
+    let mut parts = vec![];
+    let (media_type, parameters) = parse_parameters(&content_type(header));
+    if media_type.starts_with("multipart/") {
+        if let Some((_, boundary)) = parameters.iter().find(|(name, _)| name == "boundary") {
+            let marker = format!("--{}", boundary).into_bytes();
+
+            // (début de la ligne, fin de la ligne, délimiteur de fin ?)
+            let mut delimiters = vec![];
+            let mut position = 0;
+            while position < body.len() {
+                let line_end = body[position..]
+                    .windows(2)
+                    .position(|window| window == b"\r\n")
+                    .map_or(body.len(), |end| position + end + 2);
+                let line = &body[position..line_end];
+                if line.starts_with(&marker) {
+                    delimiters.push((position, line_end, line[marker.len()..].starts_with(b"--")));
+                }
+                position = line_end;
+            }
+
+            for pair in delimiters.windows(2) {
+                let (_, start, closing) = pair[0];
+                if closing {
+                    break;
+                }
+                // Le CRLF qui précède un délimiteur fait partie du délimiteur
+                let end = pair[1].0.saturating_sub(2).max(start);
+                parts.push(parse(&body[start..end]));
+            }
+        }
+    }
+
+    Entity {
+        header,
+        body,
+        parts,
+    }
+}
+
+fn content_type(header: &[u8]) -> String {
+    field(header, "Content-Type").unwrap_or_else(|| "text/plain; charset=us-ascii".to_string())
+}
+
+fn istring(value: String) -> IString<'static> {
+    // unwrap: seul un octet nul est refusé, et il n'y en a pas dans nos en-têtes
+    IString::try_from(value.replace('\0', "")).unwrap()
+}
+
+impl<'a> Entity<'a> {
+    pub fn is_multipart(&self) -> bool {
+        !self.parts.is_empty()
+    }
+
+    // Partie désignée par un chemin du type 1.2 (BODY[1.2])
+    pub fn part(&self, path: &[NonZeroU32]) -> Option<&Entity<'a>> {
+        match path.split_first() {
+            None => Some(self),
+            Some((first, rest)) if self.is_multipart() => {
+                self.parts.get(first.get() as usize - 1)?.part(rest)
+            }
+            // Une partie simple est sa propre (et unique) sous-partie 1
+            Some((first, rest)) if first.get() == 1 && rest.is_empty() => Some(self),
+            _ => None,
+        }
+    }
+
+    // Corps décodé selon son Content-Transfer-Encoding
+    pub fn decoded(&self) -> Vec<u8> {
+        match field(self.header, "Content-Transfer-Encoding") {
+            Some(encoding) if encoding.eq_ignore_ascii_case("base64") => {
+                let encoded: Vec<u8> = self
+                    .body
+                    .iter()
+                    .copied()
+                    .filter(|c| !c.is_ascii_whitespace())
+                    .collect();
+                base64.decode(encoded).unwrap_or_default()
+            }
+            _ => self.body.to_vec(),
+        }
+    }
+
+    // Texte des parties text/*, décodé, dans l'ordre du message
+    pub fn text(&self) -> Vec<(String, String)> {
+        if self.is_multipart() {
+            return self.parts.iter().flat_map(Entity::text).collect();
+        }
+        let (media_type, _) = parse_parameters(&content_type(self.header));
+        let media_type = media_type.to_lowercase();
+        if !media_type.starts_with("text/") {
+            return vec![];
+        }
+        vec![(
+            media_type,
+            String::from_utf8_lossy(&self.decoded()).into_owned(),
+        )]
+    }
+
+    pub fn body_structure(&self, extended: bool) -> BodyStructure<'static> {
+        let (media_type, parameters) = parse_parameters(&content_type(self.header));
+        let (r#type, subtype) = media_type
+            .split_once('/')
+            .map(|(r#type, subtype)| (r#type.to_uppercase(), subtype.to_uppercase()))
+            .unwrap_or(("TEXT".to_string(), "PLAIN".to_string()));
+        let parameter_list: Vec<_> = parameters
+            .into_iter()
+            .map(|(name, value)| (istring(name.to_uppercase()), istring(value)))
+            .collect();
+
+        if self.is_multipart() {
+            // unwrap: on vient de vérifier qu'il y a des parties
+            let bodies = NonEmptyVec::try_from(
+                self.parts
+                    .iter()
+                    .map(|part| part.body_structure(extended))
+                    .collect::<Vec<_>>(),
+            )
+            .unwrap();
+            return BodyStructure::Multi {
+                bodies,
+                subtype: istring(subtype),
+                extension_data: extended.then_some(MultiPartExtensionData {
+                    parameter_list,
+                    tail: None,
+                }),
+            };
+        }
+
+        let basic = BasicFields {
+            parameter_list,
+            id: NString(None),
+            description: NString(None),
+            content_transfer_encoding: istring(
+                field(self.header, "Content-Transfer-Encoding")
+                    .unwrap_or_else(|| "7bit".to_string())
+                    .to_uppercase(),
+            ),
+            size: self.body.len() as u32,
+        };
+        let specific = if r#type == "TEXT" {
+            SpecificFields::Text {
+                subtype: istring(subtype),
+                number_of_lines: self.body.split(|&c| c == b'\n').count() as u32 - 1,
+            }
+        } else {
+            SpecificFields::Basic {
+                r#type: istring(r#type),
+                subtype: istring(subtype),
+            }
+        };
+        let disposition = field(self.header, "Content-Disposition").map(|value| {
+            let (disposition, parameters) = parse_parameters(&value);
+            (
+                istring(disposition.to_uppercase()),
+                parameters
+                    .into_iter()
+                    .map(|(name, value)| (istring(name.to_uppercase()), istring(value)))
+                    .collect(),
+            )
+        });
+
+        BodyStructure::Single {
+            body: Body { basic, specific },
+            extension_data: extended.then_some(SinglePartExtensionData {
+                md5: NString(None),
+                tail: Some(Disposition {
+                    disposition,
+                    tail: None,
+                }),
+            }),
+        }
+    }
+}
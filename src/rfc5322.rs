@@ -0,0 +1,136 @@
+use base64::{engine::general_purpose::STANDARD as base64, Engine};
+use imap_codec::imap_types::{
+    core::{AString, NonEmptyVec},
+    fetch::Section,
+};
+use std::num::NonZeroU32;
+
+use crate::api::{Message, MessageSummary, Person};
+use crate::{message, mime};
+
+// Mot encodé (RFC 2047) si le texte n'est pas de l'ASCII affichable
+pub fn encode_word(text: &str) -> String {
+    if text.chars().all(|c| c.is_ascii() && !c.is_ascii_control()) {
+        text.to_string()
+    } else {
+        format!("=?UTF-8?B?{}?=", base64.encode(text))
+    }
+}
+
+pub fn address(person: &Person) -> String {
+    let address = format!("{}@{}", message::local_part(person), message::HOST);
+    match message::display_name(person) {
+        Some(name) if name.chars().all(|c| c.is_ascii_alphanumeric() || c == ' ') => {
+            format!("{} <{}>", name, address)
+        }
+        Some(name) if name.is_ascii() => {
+            format!("\"{}\" <{}>", name.replace(['\\', '"'], ""), address)
+        }
+        Some(name) => format!("{} <{}>", encode_word(&name), address),
+        None => address,
+    }
+}
+
+pub fn headers(message: &MessageSummary) -> Vec<(&'static str, String)> {
+    let mut headers = vec![("Message-ID", message::message_id(message))];
+
+    if let Some(date) = message::date(message) {
+        headers.push(("Date", date.to_rfc2822()));
+    }
+    if let Some(from) = &message.from {
+        headers.push(("From", address(from)));
+    }
+    match message.to.as_slice() {
+        [] => headers.push(("To", "undisclosed-recipients:;".to_string())),
+        to => headers.push(("To", to.iter().map(address).collect::<Vec<_>>().join(", "))),
+    }
+    if !message.subject.is_empty() {
+        headers.push(("Subject", encode_word(&message.subject)));
+    }
+    headers.push(("MIME-Version", "1.0".to_string()));
+    headers
+}
+
+// Les pièces jointes sont dans l'ordre de `message.files`
+pub fn build(message: &Message, attachments: &[Vec<u8>]) -> Vec<u8> {
+    let mut raw = Vec::new();
+    for (name, value) in headers(&message.summary) {
+        raw.extend_from_slice(format!("{}: {}\r\n", name, value).as_bytes());
+    }
+    // Le corps MIME commence par ses propres en-têtes Content-*
+    raw.extend_from_slice(&mime::build(message, attachments));
+    raw
+}
+
+// Position du début du corps (après la ligne vide qui suit les en-têtes)
+fn body_start(raw: &[u8]) -> usize {
+    raw.windows(4)
+        .position(|window| window == b"\r\n\r\n")
+        .map_or(raw.len(), |position| position + 4)
+}
+
+pub fn header(raw: &[u8]) -> &[u8] {
+    &raw[..body_start(raw)]
+}
+
+pub fn text(raw: &[u8]) -> &[u8] {
+    &raw[body_start(raw)..]
+}
+
+pub fn header_fields(raw: &[u8], names: &NonEmptyVec<AString<'_>>, not: bool) -> Vec<u8> {
+    let header = header(raw);
+    let mut selected = Vec::new();
+    let mut keep = false;
+
+    for line in header.split_inclusive(|&c| c == b'\n') {
+        if line == b"\r\n" {
+            break;
+        }
+        // Les lignes qui commencent par un blanc continuent le champ précédent
+        if !line.starts_with(b" ") && !line.starts_with(b"\t") {
+            let name = line.split(|&c| c == b':').next().unwrap_or(b"");
+            keep = names
+                .as_ref()
+                .iter()
+                .any(|wanted| wanted.as_ref().eq_ignore_ascii_case(name))
+                != not;
+        }
+        if keep {
+            selected.extend_from_slice(line);
+        }
+    }
+
+    selected.extend_from_slice(b"\r\n");
+    selected
+}
+
+pub fn section(raw: &[u8], section: &Option<Section<'_>>) -> Option<Vec<u8>> {
+    match section {
+        None => Some(raw.to_vec()),
+        Some(Section::Header(None)) => Some(header(raw).to_vec()),
+        Some(Section::Text(None)) => Some(text(raw).to_vec()),
+        Some(Section::HeaderFields(None, names)) => Some(header_fields(raw, names, false)),
+        Some(Section::HeaderFieldsNot(None, names)) => Some(header_fields(raw, names, true)),
+        Some(Section::Part(part)) => {
+            let entity = mime::parse(raw);
+            Some(entity.part(part.0.as_ref())?.body.to_vec())
+        }
+        Some(Section::Mime(part)) => {
+            let entity = mime::parse(raw);
+            Some(entity.part(part.0.as_ref())?.header.to_vec())
+        }
+        // Il n'y a pas de message/rfc822 dans les messages EcoleDirecte
+        _ => None,
+    }
+}
+
+pub fn partial(data: &[u8], partial: Option<(u32, NonZeroU32)>) -> &[u8] {
+    match partial {
+        None => data,
+        Some((start, length)) => {
+            let start = (start as usize).min(data.len());
+            let end = start.saturating_add(length.get() as usize).min(data.len());
+            &data[start..end]
+        }
+    }
+}
@@ -0,0 +1,193 @@
+use imap_codec::imap_types::{
+    core::{AString, Charset},
+    flag::Flag,
+    search::SearchKey,
+};
+use std::num::NonZeroU32;
+
+use crate::api::{MessageSummary, Person};
+use crate::{mailbox, message, mime, rfc5322};
+
+// Seuls les jeux de caractères dont on sait comparer les chaînes
+pub const CHARSETS: [&str; 2] = ["UTF-8", "US-ASCII"];
+
+pub fn supported(charset: &Option<Charset<'_>>) -> bool {
+    match charset {
+        None => true,
+        Some(charset) => CHARSETS
+            .iter()
+            .any(|supported| supported.eq_ignore_ascii_case(charset.as_ref())),
+    }
+}
+
+// Un message du dossier sélectionné, examiné par SEARCH.
+//
+// Le message complet (`raw`) n'est récupéré que si un critère en a besoin
+// (voir `needs_raw`).
+pub struct Candidate<'a> {
+    pub message: &'a MessageSummary,
+    pub number: u32,
+    pub uid: NonZeroU32,
+    // Plus grand numéro de séquence et plus grand UID, pour "*"
+    pub largest: u32,
+    pub largest_uid: u32,
+    pub raw: Option<Vec<u8>>,
+}
+
+impl<'a> Candidate<'a> {
+    fn raw(&self) -> &[u8] {
+        self.raw.as_deref().unwrap_or_default()
+    }
+}
+
+// BODY, TEXT, LARGER et SMALLER ont besoin du message complet
+pub fn needs_raw(key: &SearchKey<'_>) -> bool {
+    match key {
+        SearchKey::And(keys) => keys.as_ref().iter().any(needs_raw),
+        SearchKey::Or(left, right) => needs_raw(left) || needs_raw(right),
+        SearchKey::Not(key) => needs_raw(key),
+        SearchKey::Larger(_) | SearchKey::Smaller(_) | SearchKey::Body(_) | SearchKey::Text(_) => {
+            true
+        }
+        _ => false,
+    }
+}
+
+// Comparaison de sous-chaîne insensible à la casse (RFC 3501 6.4.4)
+fn contains(haystack: &str, needle: &AString<'_>) -> bool {
+    haystack
+        .to_lowercase()
+        .contains(&String::from_utf8_lossy(needle.as_ref()).to_lowercase())
+}
+
+// Nom affiché et adresse, sans encodage RFC 2047
+fn person(person: &Person) -> String {
+    let address = format!("{}@{}", message::local_part(person), message::HOST);
+    match message::display_name(person) {
+        Some(name) => format!("{} <{}>", name, address),
+        None => address,
+    }
+}
+
+fn recipients(message: &MessageSummary) -> Vec<String> {
+    message.to.iter().map(person).collect()
+}
+
+// Valeurs des en-têtes du message, sans encodage RFC 2047
+fn header_values(message: &MessageSummary) -> Vec<(&'static str, String)> {
+    rfc5322::headers(message)
+        .into_iter()
+        .map(|(name, value)| match name {
+            "From" => (name, message.from.as_ref().map(person).unwrap_or_default()),
+            "To" => (name, recipients(message).join(", ")),
+            "Subject" => (name, message.subject.clone()),
+            _ => (name, value),
+        })
+        .collect()
+}
+
+// Retire les balises et les entités les plus courantes du HTML
+fn strip_tags(html: &str) -> String {
+    let mut text = String::with_capacity(html.len());
+    let mut in_tag = false;
+    for c in html.chars() {
+        match c {
+            '<' => in_tag = true,
+            '>' if in_tag => {
+                in_tag = false;
+                text.push(' ');
+            }
+            _ if !in_tag => text.push(c),
+            _ => (),
+        }
+    }
+    text.replace("&nbsp;", " ")
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&amp;", "&")
+}
+
+fn body_text(raw: &[u8]) -> String {
+    mime::parse(raw)
+        .text()
+        .into_iter()
+        .map(|(media_type, text)| {
+            if media_type == "text/html" {
+                strip_tags(&text)
+            } else {
+                text
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+fn has_flag(message: &MessageSummary, flag: &Flag<'_>) -> bool {
+    message::flags(message).contains(flag)
+}
+
+pub fn matches(key: &SearchKey<'_>, candidate: &Candidate<'_>) -> bool {
+    let message = candidate.message;
+    // La date interne est celle de l'en-tête Date : celle de l'envoi
+    let date = message::date(message).map(|date| date.date_naive());
+
+    match key {
+        SearchKey::All => true,
+        SearchKey::And(keys) => keys.as_ref().iter().all(|key| matches(key, candidate)),
+        SearchKey::Or(left, right) => matches(left, candidate) || matches(right, candidate),
+        SearchKey::Not(key) => !matches(key, candidate),
+        SearchKey::SequenceSet(set) => mailbox::contains(set, candidate.number, candidate.largest),
+        SearchKey::Uid(set) => mailbox::contains(set, candidate.uid.get(), candidate.largest_uid),
+
+        SearchKey::Answered => has_flag(message, &Flag::Answered),
+        SearchKey::Unanswered => !has_flag(message, &Flag::Answered),
+        SearchKey::Deleted => has_flag(message, &Flag::Deleted),
+        SearchKey::Undeleted => !has_flag(message, &Flag::Deleted),
+        SearchKey::Draft => has_flag(message, &Flag::Draft),
+        SearchKey::Undraft => !has_flag(message, &Flag::Draft),
+        SearchKey::Flagged => has_flag(message, &Flag::Flagged),
+        SearchKey::Unflagged => !has_flag(message, &Flag::Flagged),
+        SearchKey::Seen => has_flag(message, &Flag::Seen),
+        SearchKey::Unseen => !has_flag(message, &Flag::Seen),
+        SearchKey::Keyword(keyword) => has_flag(message, &Flag::Keyword(keyword.clone())),
+        SearchKey::Unkeyword(keyword) => !has_flag(message, &Flag::Keyword(keyword.clone())),
+        // Aucun message n'est \Recent : EcoleDirecte ne permet pas de le savoir
+        SearchKey::Recent | SearchKey::New => false,
+        SearchKey::Old => true,
+
+        SearchKey::From(value) => message
+            .from
+            .as_ref()
+            .is_some_and(|from| contains(&person(from), value)),
+        SearchKey::To(value) => recipients(message).iter().any(|to| contains(to, value)),
+        // Les messages EcoleDirecte n'ont ni copie ni copie cachée
+        SearchKey::Cc(_) | SearchKey::Bcc(_) => false,
+        SearchKey::Subject(value) => contains(&message.subject, value),
+        SearchKey::Header(name, value) => header_values(message)
+            .iter()
+            .filter(|(field, _)| field.as_bytes().eq_ignore_ascii_case(name.as_ref()))
+            .any(|(_, field)| contains(field, value)),
+
+        SearchKey::Before(day) | SearchKey::SentBefore(day) => {
+            date.is_some_and(|date| date < *day.as_ref())
+        }
+        SearchKey::On(day) | SearchKey::SentOn(day) => {
+            date.is_some_and(|date| date == *day.as_ref())
+        }
+        SearchKey::Since(day) | SearchKey::SentSince(day) => {
+            date.is_some_and(|date| date >= *day.as_ref())
+        }
+
+        SearchKey::Larger(size) => candidate.raw().len() > *size as usize,
+        SearchKey::Smaller(size) => candidate.raw().len() < *size as usize,
+        SearchKey::Body(value) => contains(&body_text(candidate.raw()), value),
+        SearchKey::Text(value) => {
+            header_values(message)
+                .iter()
+                .any(|(_, field)| contains(field, value))
+                || contains(&body_text(candidate.raw()), value)
+        }
+    }
+}
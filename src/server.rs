@@ -0,0 +1,2420 @@
+use futures_util::{SinkExt, StreamExt};
+use imap_codec::{
+    encode::Encoder,
+    imap_types::{
+        self,
+        auth::AuthMechanism,
+        bounded_static::IntoBoundedStatic,
+        command::{Command, CommandBody},
+        core::{AString, Charset, LiteralMode, NonEmptyVec, Tag, Text},
+        fetch::{MacroOrMessageDataItemNames, MessageDataItem, MessageDataItemName},
+        flag::StoreResponse,
+        mailbox::{ListMailbox, Mailbox},
+        response::{
+            Capability, Code, CommandContinuationRequest, Data, Greeting, GreetingKind, Response,
+            Status,
+        },
+        secret::Secret,
+        state::State,
+    },
+    CommandCodec, ResponseCodec,
+};
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::io;
+use std::num::NonZeroU32;
+use std::str;
+use std::sync::Arc;
+use tokio::time;
+use tokio_util::codec::Framed;
+
+use crate::api::{ApiError, Folder, MessageSummary};
+use crate::auth;
+use crate::backend::MailBackend;
+use crate::capabilities;
+use crate::code;
+use crate::codec::{Expected, Frame, ImapCodec};
+use crate::config;
+use crate::failure;
+use crate::idle_interval;
+use crate::mailbox;
+use crate::message;
+use crate::rfc5322;
+use crate::search;
+use crate::store::Store;
+use crate::tls::{Stream, Tls};
+use crate::uid::UidMap;
+
+pub struct Connection<'a> {
+    state: State<'a>,
+    user: Option<auth::User>,
+    folders: Option<HashMap<String, u32>>,
+    // Messages du dossier sélectionné, dans l'ordre des numéros de séquence
+    messages: Vec<MessageSummary>,
+    // UIDs du dossier sélectionné
+    uids: Option<UidMap>,
+    // Données conservées entre les connexions pour le compte connecté
+    store: Option<Store>,
+    // Dossier ouvert par EXAMINE
+    read_only: bool,
+    // LIST (SPECIAL-USE), voir `mailbox::list_extended`
+    list_special_use: bool,
+    // Certificat pour STARTTLS, s'il y en a un
+    tls: Option<Arc<Tls>>,
+}
+
+impl<'a> Default for Connection<'a> {
+    fn default() -> Connection<'a> {
+        Connection {
+            state: State::Greeting,
+            user: None,
+            folders: None,
+            messages: Vec::new(),
+            uids: None,
+            store: None,
+            read_only: false,
+            list_special_use: false,
+            tls: None,
+        }
+    }
+}
+
+impl<'a> Connection<'a> {
+    pub fn new(tls: Option<Arc<Tls>>) -> Connection<'a> {
+        Connection {
+            tls,
+            ..Connection::default()
+        }
+    }
+}
+
+// Le mot de passe ne doit pas passer en clair quand le client peut chiffrer la
+// connexion avec STARTTLS (sauf si la configuration l'autorise)
+fn login_disabled(connection: &Connection<'_>, stream: &Stream) -> bool {
+    connection.tls.is_some() && !stream.is_encrypted() && !config::get().tls.plaintext_login
+}
+
+// Avant l'authentification, les capacités dépendent de la connexion : STARTTLS
+// tant qu'elle est en clair, et LOGINDISABLED sans AUTH=PLAIN si on refuse
+// d'y recevoir le mot de passe
+fn connection_capabilities(
+    connection: &Connection<'_>,
+    stream: &Stream,
+) -> NonEmptyVec<Capability<'static>> {
+    let mut capabilities = capabilities().into_inner();
+    if let State::Greeting | State::NotAuthenticated = connection.state {
+        if connection.tls.is_some() && !stream.is_encrypted() {
+            capabilities.push(Capability::StartTls);
+        }
+        if login_disabled(connection, stream) {
+            capabilities.retain(|capability| !matches!(capability, Capability::Auth(_)));
+            capabilities.push(Capability::LoginDisabled);
+        }
+    }
+    // unwrap: IMAP4rev1 est toujours là
+    NonEmptyVec::try_from(capabilities).unwrap()
+}
+
+// La connexion avec le client, découpée en commandes (voir `ImapCodec`)
+type Transport = Framed<Stream, ImapCodec>;
+
+pub async fn responder<B: MailBackend>(
+    stream: Stream,
+    mut connection: Connection<'_>,
+    backend: &B,
+) {
+    let mut transport = Framed::new(stream, ImapCodec::default());
+
+    let greeting = Greeting {
+        kind: GreetingKind::Ok,
+        code: Some(Code::Capability(connection_capabilities(
+            &connection,
+            transport.get_ref(),
+        ))),
+        text: Text::try_from("ecoledirecte-imap ready").unwrap(),
+    };
+    if transport.send(&greeting).await.is_err() {
+        return;
+    }
+
+    connection.state = State::NotAuthenticated;
+
+    while let Some(Ok(frame)) = transport.next().await {
+        let (command, list_special_use) = match frame {
+            Frame::Command {
+                command,
+                list_special_use,
+            } => (command, list_special_use),
+            frame => {
+                if send(&mut transport, &[frame_response(frame)])
+                    .await
+                    .is_err()
+                {
+                    break;
+                }
+                continue;
+            }
+        };
+        if log::log_enabled!(log::Level::Debug) {
+            log::debug!("C: {}", loggable(&command));
+        }
+        connection.list_special_use = list_special_use;
+
+        match (&command.body, &connection.state) {
+            (CommandBody::Idle, State::Authenticated | State::Selected(_)) => {
+                if !idle(command.tag, &mut connection, &mut transport, backend).await {
+                    break;
+                }
+                continue;
+            }
+            (CommandBody::StartTLS, _) => {
+                match start_tls(command.tag, &connection, transport).await {
+                    Some(encrypted) => transport = encrypted,
+                    None => break,
+                }
+                continue;
+            }
+            _ => (),
+        }
+
+        let responses = process(command, &mut connection, &mut transport, backend).await;
+        if send(&mut transport, &responses).await.is_err() {
+            break;
+        }
+        drop(responses);
+
+        connection.list_special_use = false;
+
+        if let State::Logout = connection.state {
+            break;
+        }
+    }
+}
+
+// La commande telle qu'on l'écrit dans le journal : le mot de passe de LOGIN
+// et la réponse initiale d'AUTHENTICATE sont masqués
+fn loggable(command: &Command<'_>) -> String {
+    let mut command = command.clone();
+    match &mut command.body {
+        CommandBody::Login { password, .. } => {
+            // unwrap: "***" est une chaîne IMAP valide
+            *password = Secret::new(AString::try_from("***").unwrap());
+        }
+        CommandBody::Authenticate {
+            initial_response, ..
+        } => *initial_response = None,
+        _ => (),
+    }
+    String::from_utf8_lossy(&CommandCodec::default().encode(&command).dump())
+        .trim_end()
+        .to_string()
+}
+
+// Réponse aux données du client qui ne forment pas (encore) une commande
+fn frame_response(frame: Frame) -> Response<'static> {
+    match frame {
+        Frame::LiteralAccepted => Response::CommandContinuationRequest(
+            CommandContinuationRequest::basic(None, "Ready for literal data").unwrap(),
+        ),
+        // Le client qui attendait la continuation n'a rien envoyé : NO suffit.
+        // Un littéral non synchrone trop grand est une erreur (RFC 7888, 4).
+        Frame::LiteralTooBig {
+            tag,
+            mode: LiteralMode::Sync,
+        } => Response::Status(Status::no(tag, Some(Code::TooBig), "Literal too big").unwrap()),
+        Frame::LiteralTooBig {
+            tag,
+            mode: LiteralMode::NonSync,
+        } => Response::Status(Status::bad(tag, Some(Code::TooBig), "Literal too big").unwrap()),
+        Frame::CommandTooLong { tag } => {
+            Response::Status(Status::bad(tag, None, "Command too long").unwrap())
+        }
+        _ => Response::Status(Status::bad(None, None, "Parsing failed").unwrap()),
+    }
+}
+
+async fn send(transport: &mut Transport, responses: &[Response<'_>]) -> io::Result<()> {
+    for response in responses {
+        log::debug!(
+            "S: {}",
+            String::from_utf8_lossy(&ResponseCodec::default().encode(response).dump()).trim_end()
+        );
+        transport.feed(response).await?;
+    }
+    SinkExt::<&Response>::flush(transport).await
+}
+
+// STARTTLS (RFC 3501, 6.2.1). Renvoie la connexion chiffrée, ou rien si la
+// négociation a échoué (la connexion est alors inutilisable).
+async fn start_tls(
+    tag: Tag<'static>,
+    connection: &Connection<'_>,
+    mut transport: Transport,
+) -> Option<Transport> {
+    let tls = match (&connection.tls, &connection.state) {
+        (Some(tls), State::NotAuthenticated) if !transport.get_ref().is_encrypted() => tls.clone(),
+        _ => {
+            let response =
+                Response::Status(Status::bad(Some(tag), None, "STARTTLS not available").unwrap());
+            send(&mut transport, &[response]).await.ok()?;
+            return Some(transport);
+        }
+    };
+    let response =
+        Response::Status(Status::ok(Some(tag), None, "Begin TLS negotiation now").unwrap());
+    send(&mut transport, &[response]).await.ok()?;
+
+    // Ce que le client a envoyé après STARTTLS est en clair : on l'ignore
+    // plutôt que de l'exécuter une fois chiffré
+    let stream = transport.into_parts().io;
+    match stream.start_tls(&tls).await {
+        Ok(stream) => Some(Framed::new(stream, ImapCodec::default())),
+        Err(error) => {
+            log::warn!("Échec de la négociation TLS : {}", error);
+            None
+        }
+    }
+}
+
+// IDLE (RFC 2177) : en attendant DONE, on interroge l'API à intervalles
+// réguliers pour signaler les changements du dossier sélectionné. Renvoie
+// faux si le client s'est déconnecté.
+async fn idle<B: MailBackend>(
+    tag: Tag<'static>,
+    connection: &mut Connection<'_>,
+    transport: &mut Transport,
+    backend: &B,
+) -> bool {
+    let continuation = Response::CommandContinuationRequest(
+        CommandContinuationRequest::basic(None, "idling").unwrap(),
+    );
+    if send(transport, &[continuation]).await.is_err() {
+        return false;
+    }
+
+    transport.codec_mut().expect(Expected::IdleDone);
+    let status = loop {
+        // Sans rien à lire pendant l'intervalle, on interroge l'API
+        match time::timeout(idle_interval(), transport.next()).await {
+            Ok(Some(Ok(Frame::IdleDone))) => {
+                break Some(Status::ok(Some(tag), None, "IDLE terminated"))
+            }
+            Ok(Some(Ok(_))) => break Some(Status::bad(Some(tag), None, "Expected DONE")),
+            Ok(None | Some(Err(_))) => break None,
+            Err(_) => {
+                let responses = poll(connection, backend).await;
+                if send(transport, &responses).await.is_err() {
+                    break None;
+                }
+            }
+        }
+    };
+    transport.codec_mut().expect(Expected::Command);
+
+    match status {
+        Some(status) => send(transport, &[Response::Status(status.unwrap())])
+            .await
+            .is_ok(),
+        None => false,
+    }
+}
+
+// Messages du dossier d'après la réponse de l'API, avec les drapeaux
+// conservés localement
+fn load_messages(store: &Store, name: &str, folder: &mut Folder) -> Vec<MessageSummary> {
+    let mut messages = mailbox::messages(name, folder);
+    let local_flags = store.flags();
+    for message in &mut messages {
+        if let Some(flags) = local_flags.get(&message.id) {
+            message.local_flags = flags.clone();
+        }
+    }
+    messages
+}
+
+// Changements du dossier sélectionné depuis la dernière fois : messages
+// disparus (EXPUNGE), lus ou non lus ailleurs (FETCH) et nouveaux (EXISTS)
+async fn poll<B: MailBackend>(
+    connection: &mut Connection<'_>,
+    backend: &B,
+) -> Vec<Response<'static>> {
+    let State::Selected(selected) = &connection.state else {
+        return vec![];
+    };
+    // unwrap: on est en selected
+    let user = connection.user.as_ref().unwrap();
+    let store = connection.store.as_ref().unwrap();
+    let uids = connection.uids.as_mut().unwrap();
+    let name = &*mailbox::name(selected);
+    let Some(&id) = connection
+        .folders
+        .as_ref()
+        .and_then(|folders| folders.get(name))
+    else {
+        return vec![];
+    };
+
+    // Sans passer par le cache, qui peut avoir jusqu'à une minute. En cas
+    // d'erreur, on réessaiera à l'intervalle suivant.
+    let mut folder = match backend.folder(user, id, mailbox::kind(name)).await {
+        Ok(folder) => folder,
+        Err(error) => {
+            log::warn!("Erreur de l'API : {}", error);
+            return vec![];
+        }
+    };
+    store.save_folder(&mailbox::key(name, id), &folder);
+    let listed = load_messages(store, name, &mut folder);
+    uids.assign(&listed);
+    let mut current: HashMap<_, _> = listed
+        .into_iter()
+        .map(|message| (message.id, message))
+        .collect();
+
+    let mut response = vec![];
+    // Du dernier au premier pour que les numéros restent valables
+    for index in (0..connection.messages.len()).rev() {
+        if !current.contains_key(&connection.messages[index].id) {
+            connection.messages.remove(index);
+            // unwrap: index + 1 > 0
+            response.push(Response::Data(Data::Expunge(
+                NonZeroU32::new(index as u32 + 1).unwrap(),
+            )));
+        }
+    }
+
+    for (index, message) in connection.messages.iter_mut().enumerate() {
+        // unwrap: les messages restants sont tous dans `current`
+        let updated = current.remove(&message.id).unwrap();
+        if message::flags(&updated) != message::flags(message) {
+            *message = updated;
+            let flags = message::item(message, None, &MessageDataItemName::Flags);
+            if let Ok(data) = Data::fetch(index as u32 + 1, flags.into_iter().collect::<Vec<_>>()) {
+                response.push(Response::Data(data));
+            }
+        }
+    }
+
+    if !current.is_empty() {
+        let mut new: Vec<_> = current.into_values().collect();
+        new.sort_by_key(|message| uids.get(message.id));
+        connection.messages.extend(new);
+        response.push(Response::Data(Data::Exists(
+            connection.messages.len() as u32
+        )));
+    }
+    response
+}
+
+async fn build_message<B: MailBackend>(
+    backend: &B,
+    id: u64,
+    mode: &str,
+    user: &auth::User,
+) -> Result<Vec<u8>, ApiError> {
+    let message = backend.message(user, id, mode).await?;
+    let mut attachments = vec![];
+    for file in &message.files {
+        attachments.push(backend.attachment(user, file).await?);
+    }
+    Ok(rfc5322::build(&message, &attachments))
+}
+
+// Le message complet, depuis le cache ou à défaut construit à partir de l'API.
+// L'API marque le message comme lu dès qu'on en récupère le contenu : s'il ne
+// l'était pas (`read`), on le remet comme non lu.
+async fn raw_message<B: MailBackend>(
+    backend: &B,
+    store: &Store,
+    user: &auth::User,
+    mailbox: &str,
+    id: u64,
+    read: bool,
+) -> Result<Vec<u8>, ApiError> {
+    if let Some(raw) = store.body(id) {
+        return Ok(raw);
+    }
+    let raw = build_message(backend, id, mailbox::mode(mailbox), user).await?;
+    store.save_body(id, &raw);
+    if !read {
+        backend.mark_read(user, &[id], false).await?;
+    }
+    Ok(raw)
+}
+
+async fn folder_info<B: MailBackend>(
+    backend: &B,
+    store: &Store,
+    user: &auth::User,
+    name: &str,
+    id: u32,
+) -> Result<Folder, ApiError> {
+    let key = mailbox::key(name, id);
+    if let Some(folder) = store.folder(&key) {
+        return Ok(folder);
+    }
+    let folder = backend.folder(user, id, mailbox::kind(name)).await?;
+    store.save_folder(&key, &folder);
+    Ok(folder)
+}
+
+async fn get_folders<B: MailBackend>(
+    backend: &B,
+    store: &Store,
+    user: &auth::User,
+) -> Result<HashMap<String, u32>, ApiError> {
+    Ok(mailbox::make_folders(mailbox::classeurs(
+        &folder_info(backend, store, user, "INBOX", 0).await?,
+    )))
+}
+
+// Le résultat d'un appel à l'API, ou la réponse d'erreur à la commande
+// (voir `failure`)
+macro_rules! api {
+    ($result:expr, $tag:expr) => {
+        match $result {
+            Ok(value) => value,
+            Err(error) => return vec![failure($tag, &error)],
+        }
+    };
+}
+
+// Les UIDs d'un dossier, ou la réponse d'erreur à la commande : sans le
+// fichier, on risquerait de redonner des UIDs déjà vus par le client
+macro_rules! uids {
+    ($store:expr, $key:expr, $tag:expr) => {
+        match $store.uids(&$key) {
+            Ok(uids) => uids,
+            Err(error) => {
+                log::error!("Impossible de lire les UIDs de {}: {}", $key, error);
+                return vec![Response::Status(
+                    Status::no(Some($tag), Some(code("UNAVAILABLE")), "Cannot read UIDs").unwrap(),
+                )];
+            }
+        }
+    };
+}
+
+async fn process<'a, B: MailBackend>(
+    command: Command<'a>,
+    connection: &'a mut Connection<'_>,
+    transport: &mut Transport,
+    backend: &B,
+) -> Vec<Response<'a>> {
+    use imap_types::{
+        command::CommandBody::{Logout, Status as StatusCommand},
+        command::{CommandBody, CommandBody::*},
+        response::Status,
+        state::State::{Logout as LogoutState, *},
+    };
+    // Pour ne pas confondre avec la commande STORE
+    use crate::store::Store;
+
+    if !mailbox::valid_names(&command.body) {
+        return vec![Response::Status(
+            Status::bad(Some(command.tag), None, "Mailbox name must be valid UTF-8").unwrap(),
+        )];
+    }
+
+    // Déplacement partiel (tag reste possédé par command)
+    match command.body {
+        Capability => {
+            return vec![
+                Response::Data(Data::Capability(connection_capabilities(
+                    connection,
+                    transport.get_ref(),
+                ))),
+                Response::Status(
+                    Status::ok(Some(command.tag), None, "CAPABILITY completed").unwrap(),
+                ),
+            ]
+        }
+        Noop => {
+            return vec![Response::Status(
+                Status::ok(Some(command.tag), None, "NOOP completed").unwrap(),
+            )]
+        }
+        Logout => {
+            connection.state = LogoutState;
+            return vec![
+                Response::Status(Status::bye(None, "Logging out!").unwrap()),
+                Response::Status(Status::ok(Some(command.tag), None, "LOGOUT completed").unwrap()),
+            ];
+        }
+        _ => (),
+    }
+
+    if connection.state == NotAuthenticated {
+        if let (Authenticate { .. } | Login { .. }, true) = (
+            &command.body,
+            login_disabled(connection, transport.get_ref()),
+        ) {
+            return vec![Response::Status(
+                Status::no(
+                    Some(command.tag),
+                    Some(code("PRIVACYREQUIRED")),
+                    "Use STARTTLS first",
+                )
+                .unwrap(),
+            )];
+        }
+        match command.body {
+            Authenticate {
+                mechanism,
+                initial_response,
+            } => {
+                if mechanism != AuthMechanism::Plain {
+                    return vec![Response::Status(
+                        Status::no(Some(command.tag), None, "Unsupported mechanism").unwrap(),
+                    )];
+                }
+                if initial_response.is_some() {
+                    return vec![Response::Status(
+                        Status::no(Some(command.tag), None, "Unexpected initial response").unwrap(),
+                    )];
+                }
+
+                // Les données peuvent être arrivées avec la commande : elles
+                // sont lues depuis le même tampon (voir `ImapCodec`)
+                let continuation = Response::CommandContinuationRequest(
+                    CommandContinuationRequest::Base64(Cow::Borrowed(&[])),
+                );
+                if send(transport, &[continuation]).await.is_err() {
+                    return vec![];
+                }
+                transport.codec_mut().expect(Expected::AuthenticateData);
+                let frame = transport.next().await;
+                transport.codec_mut().expect(Expected::Command);
+                let line = match frame {
+                    Some(Ok(Frame::AuthenticateData(line))) => {
+                        log::debug!("C: (identifiants masqués)");
+                        line
+                    }
+                    Some(Ok(Frame::CommandTooLong { .. })) => {
+                        return vec![Response::Status(
+                            Status::bad(Some(command.tag), None, "Line too long").unwrap(),
+                        )];
+                    }
+                    Some(Ok(_)) => {
+                        return vec![Response::Status(
+                            Status::bad(Some(command.tag), None, "Invalid BASE64 literal").unwrap(),
+                        )];
+                    }
+                    None | Some(Err(_)) => return vec![],
+                };
+
+                let (username, password) = match auth::parse_plain_message(
+                    Secret::new(line.0.declassify()),
+                    command.tag.clone(),
+                ) {
+                    Ok(tup) => tup,
+                    Err(response) => {
+                        return response;
+                    }
+                };
+
+                let (state, user, response) = auth::translate(
+                    backend.login(username, password).await,
+                    (username, password),
+                    command.tag,
+                );
+
+                connection.state = state;
+                connection.store = user.as_ref().map(|user| Store::open(user.id));
+                connection.user = user;
+                return response;
+            }
+            Login { username, password } => {
+                let (Ok(username), Ok(password)) = (
+                    str::from_utf8(username.as_ref()),
+                    str::from_utf8(password.declassify().as_ref()),
+                ) else {
+                    return vec![Response::Status(
+                        Status::no(
+                            Some(command.tag),
+                            None,
+                            "Username and password must be valid UTF-8",
+                        )
+                        .unwrap(),
+                    )];
+                };
+                let (state, user, response) = auth::translate(
+                    backend.login(username, password).await,
+                    (username, password),
+                    command.tag,
+                );
+
+                connection.state = state;
+                connection.store = user.as_ref().map(|user| Store::open(user.id));
+                connection.user = user;
+                return response;
+            }
+            _ => (),
+        }
+    }
+
+    if let Authenticated | Selected(_) = connection.state {
+        // EXAMINE est un SELECT en lecture seule
+        let read_only = matches!(command.body, Examine { .. });
+        match command.body {
+            Select { mailbox } | Examine { mailbox } => {
+                // Le dossier sélectionné est fermé même si la commande échoue
+                // (RFC 3501 6.3.1)
+                connection.state = State::Authenticated;
+                connection.messages = Vec::new();
+                connection.uids = None;
+                // unwrap: on est en authenticated ou selected
+                let user = connection.user.as_ref().unwrap();
+                let store = connection.store.as_ref().unwrap();
+                if connection.folders.is_none() {
+                    connection.folders =
+                        Some(api!(get_folders(backend, store, user).await, command.tag));
+                }
+                let folders = connection.folders.as_ref().unwrap();
+
+                let name = &*mailbox::name(&mailbox);
+                match folders.get(name) {
+                    Some(&id) => {
+                        let mut folder = api!(
+                            folder_info(backend, store, user, name, id).await,
+                            command.tag
+                        );
+                        let mut messages = load_messages(store, name, &mut folder);
+
+                        let mut uids = uids!(store, mailbox::key(name, id), command.tag);
+                        uids.assign(&messages);
+                        // Les numéros de séquence suivent l'ordre des UIDs
+                        messages.sort_by_key(|message| uids.get(message.id));
+
+                        let mut response = mailbox::mailbox_info(&messages, &uids, read_only);
+                        response.push(Response::Status(
+                            if read_only {
+                                Status::ok(
+                                    Some(command.tag),
+                                    Some(Code::ReadOnly),
+                                    "EXAMINE completed",
+                                )
+                            } else {
+                                Status::ok(
+                                    Some(command.tag),
+                                    Some(Code::ReadWrite),
+                                    "SELECT completed",
+                                )
+                            }
+                            .unwrap(),
+                        ));
+
+                        connection.state = State::Selected(mailbox.into_static());
+                        connection.read_only = read_only;
+                        connection.messages = messages;
+                        connection.uids = Some(uids);
+                        return response;
+                    }
+                    None => {
+                        connection.folders =
+                            Some(api!(get_folders(backend, store, user).await, command.tag));
+                        let folders = connection.folders.as_ref().unwrap();
+                        if folders.contains_key(name) {
+                            // Récursif : la future doit être mise dans une Box
+                            return Box::pin(process(
+                                Command {
+                                    tag: command.tag,
+                                    body: if read_only {
+                                        Examine { mailbox }
+                                    } else {
+                                        Select { mailbox }
+                                    },
+                                },
+                                connection,
+                                transport,
+                                backend,
+                            ))
+                            .await;
+                        } else {
+                            return vec![Response::Status(
+                                Status::no(Some(command.tag), None, "No such mailbox!").unwrap(),
+                            )];
+                        }
+                    }
+                }
+            }
+            Create { mailbox } => {
+                // unwrap: on est en authenticated ou selected
+                let user = connection.user.as_ref().unwrap();
+                let store = connection.store.as_ref().unwrap();
+                // "Cours/" annonce des sous-dossiers, il suffit de créer "Cours"
+                let name = &*mailbox::name(&mailbox);
+                let name = name.trim_end_matches(mailbox::DELIMITER);
+                let folders = api!(get_folders(backend, store, user).await, command.tag);
+
+                let refused = if mailbox::is_system(name) {
+                    Some((code("CANNOT"), "System folders cannot be created"))
+                } else if folders.contains_key(name) {
+                    Some((code("ALREADYEXISTS"), "Mailbox already exists"))
+                } else {
+                    None
+                };
+                if let Some((code, text)) = refused {
+                    return vec![Response::Status(
+                        Status::no(Some(command.tag), Some(code), text).unwrap(),
+                    )];
+                }
+
+                api!(backend.create_classeur(user, name).await, command.tag);
+                // La liste des classeurs vient avec les messages de l'INBOX. Si
+                // elle ne peut pas être rechargée, elle le sera à la prochaine
+                // commande qui en a besoin.
+                store.forget_folder("INBOX");
+                connection.folders = get_folders(backend, store, user).await.ok();
+                return vec![Response::Status(
+                    Status::ok(Some(command.tag), None, "CREATE completed").unwrap(),
+                )];
+            }
+            Delete { mailbox } => {
+                // unwrap: on est en authenticated ou selected
+                let user = connection.user.as_ref().unwrap();
+                let store = connection.store.as_ref().unwrap();
+                let name = &*mailbox::name(&mailbox);
+                let folders = api!(get_folders(backend, store, user).await, command.tag);
+
+                let id = match folders.get(name) {
+                    _ if mailbox::is_system(name) => {
+                        Err((code("CANNOT"), "System folders cannot be deleted"))
+                    }
+                    Some(&id) => Ok(id),
+                    None => Err((code("NONEXISTENT"), "No such mailbox!")),
+                };
+                let id = match id {
+                    Ok(id) => id,
+                    Err((code, text)) => {
+                        return vec![Response::Status(
+                            Status::no(Some(command.tag), Some(code), text).unwrap(),
+                        )]
+                    }
+                };
+
+                api!(backend.delete_classeur(user, id).await, command.tag);
+                store.forget_folder("INBOX");
+                store.forget_folder(&mailbox::key(name, id));
+                connection.folders = get_folders(backend, store, user).await.ok();
+                // Le dossier sélectionné n'existe plus : comme après CLOSE
+                if matches!(&connection.state, Selected(selected) if mailbox::name(selected) == name)
+                {
+                    connection.state = State::Authenticated;
+                    connection.messages.clear();
+                    connection.uids = None;
+                }
+                return vec![Response::Status(
+                    Status::ok(Some(command.tag), None, "DELETE completed").unwrap(),
+                )];
+            }
+            Rename { from, to } => {
+                // unwrap: on est en authenticated ou selected
+                let user = connection.user.as_ref().unwrap();
+                let store = connection.store.as_ref().unwrap();
+                let (from, to) = (&*mailbox::name(&from), &*mailbox::name(&to));
+                let folders = api!(get_folders(backend, store, user).await, command.tag);
+
+                // Les classeurs inférieurs changent de nom avec lui (RFC 3501 6.3.5)
+                let rename = |name: &str| match name.strip_prefix(from) {
+                    Some("") => Some(to.to_string()),
+                    Some(rest) if rest.starts_with(mailbox::DELIMITER) => {
+                        Some(format!("{}{}", to, rest))
+                    }
+                    _ => None,
+                };
+                let mut renamed: Vec<_> = folders
+                    .iter()
+                    .filter_map(|(name, &id)| rename(name).map(|name| (id, name)))
+                    .collect();
+                // Le classeur lui-même d'abord, puis ses inférieurs
+                renamed.sort_by_key(|(_, name)| name.len());
+
+                let id = match folders.get(from) {
+                    _ if mailbox::is_system(from) || mailbox::is_system(to) => {
+                        Err((code("CANNOT"), "System folders cannot be renamed"))
+                    }
+                    None => Err((code("NONEXISTENT"), "No such mailbox!")),
+                    Some(_) if renamed.iter().any(|(_, name)| folders.contains_key(name)) => {
+                        Err((code("ALREADYEXISTS"), "Mailbox already exists"))
+                    }
+                    Some(&id) => Ok(id),
+                };
+                if let Err((code, text)) = id {
+                    return vec![Response::Status(
+                        Status::no(Some(command.tag), Some(code), text).unwrap(),
+                    )];
+                }
+
+                let mut result = Ok(());
+                for (id, name) in &renamed {
+                    result = backend.rename_classeur(user, *id, name).await;
+                    if result.is_err() {
+                        break;
+                    }
+                }
+                // Les données des classeurs sont rangées sous leur identifiant,
+                // qui ne change pas
+                store.forget_folder("INBOX");
+                connection.folders = get_folders(backend, store, user).await.ok();
+                if let Err(error) = result {
+                    return vec![failure(command.tag, &error)];
+                }
+                if let Selected(selected) = &connection.state {
+                    if let Some(name) = rename(&mailbox::name(selected)) {
+                        // unwrap: le nom vient d'une commande déjà validée
+                        connection.state =
+                            Selected(Mailbox::try_from(mailbox::encode(&name)).unwrap());
+                    }
+                }
+                return vec![Response::Status(
+                    Status::ok(Some(command.tag), None, "RENAME completed").unwrap(),
+                )];
+            }
+            List {
+                reference,
+                mailbox_wildcard,
+            } => {
+                use imap_types::flag::FlagNameAttribute::Noselect;
+                let name = match mailbox_wildcard {
+                    ListMailbox::String(ref name) => name.as_ref(),
+                    ListMailbox::Token(ref name) => name.as_ref(),
+                };
+
+                if name.is_empty() {
+                    return vec![
+                        Response::Data(Data::List {
+                            items: vec![Noselect],
+                            delimiter: mailbox::delimiter(),
+                            mailbox: Mailbox::try_from("").unwrap(),
+                        }),
+                        Response::Status(
+                            Status::ok(Some(command.tag), None, "LIST completed").unwrap(),
+                        ),
+                    ];
+                }
+
+                // unwrap: on est en authenticated ou selected
+                let user = connection.user.as_ref().unwrap();
+                let store = connection.store.as_ref().unwrap();
+                connection.folders =
+                    Some(api!(get_folders(backend, store, user).await, command.tag));
+                let folders = connection.folders.as_ref().unwrap();
+
+                let mut response = mailbox::filter(
+                    folders.keys().map(String::as_str),
+                    &reference,
+                    name,
+                    false,
+                    connection.list_special_use,
+                );
+
+                response.push(Response::Status(
+                    Status::ok(Some(command.tag), None, "LIST completed").unwrap(),
+                ));
+                return response;
+            }
+            Lsub {
+                reference,
+                mailbox_wildcard,
+            } => {
+                let name = match mailbox_wildcard {
+                    ListMailbox::String(ref name) => name.as_ref(),
+                    ListMailbox::Token(ref name) => name.as_ref(),
+                };
+
+                // unwrap: on est en authenticated ou selected
+                let user = connection.user.as_ref().unwrap();
+                let store = connection.store.as_ref().unwrap();
+                let subscriptions = match store.subscriptions() {
+                    Some(subscriptions) => subscriptions,
+                    None => api!(get_folders(backend, store, user).await, command.tag)
+                        .into_keys()
+                        .collect(),
+                };
+
+                let mut response = mailbox::filter(
+                    subscriptions.iter().map(String::as_str),
+                    &reference,
+                    name,
+                    true,
+                    false,
+                );
+                response.push(Response::Status(
+                    Status::ok(Some(command.tag), None, "LSUB completed").unwrap(),
+                ));
+                return response;
+            }
+            Subscribe { mailbox } => {
+                // unwrap: on est en authenticated ou selected
+                let user = connection.user.as_ref().unwrap();
+                let store = connection.store.as_ref().unwrap();
+                let name = &*mailbox::name(&mailbox);
+                let folders = api!(get_folders(backend, store, user).await, command.tag);
+                if !folders.contains_key(name) {
+                    return vec![Response::Status(
+                        Status::no(
+                            Some(command.tag),
+                            Some(code("NONEXISTENT")),
+                            "No such mailbox!",
+                        )
+                        .unwrap(),
+                    )];
+                }
+
+                let mut subscriptions = store
+                    .subscriptions()
+                    .unwrap_or_else(|| folders.into_keys().collect());
+                if !subscriptions
+                    .iter()
+                    .any(|subscription| subscription == name)
+                {
+                    subscriptions.push(name.to_string());
+                }
+                store.save_subscriptions(&subscriptions);
+                return vec![Response::Status(
+                    Status::ok(Some(command.tag), None, "SUBSCRIBE completed").unwrap(),
+                )];
+            }
+            Unsubscribe { mailbox } => {
+                // unwrap: on est en authenticated ou selected
+                let user = connection.user.as_ref().unwrap();
+                let store = connection.store.as_ref().unwrap();
+                let name = &*mailbox::name(&mailbox);
+
+                let mut subscriptions = match store.subscriptions() {
+                    Some(subscriptions) => subscriptions,
+                    None => api!(get_folders(backend, store, user).await, command.tag)
+                        .into_keys()
+                        .collect(),
+                };
+                subscriptions.retain(|subscription| subscription != name);
+                store.save_subscriptions(&subscriptions);
+                return vec![Response::Status(
+                    Status::ok(Some(command.tag), None, "UNSUBSCRIBE completed").unwrap(),
+                )];
+            }
+            StatusCommand {
+                mailbox,
+                item_names,
+            } => {
+                // unwrap: on est en authenticated ou selected
+                let user = connection.user.as_ref().unwrap();
+                let store = connection.store.as_ref().unwrap();
+                if connection.folders.is_none() {
+                    connection.folders =
+                        Some(api!(get_folders(backend, store, user).await, command.tag));
+                }
+                let name = &*mailbox::name(&mailbox);
+                let id = match connection.folders.as_ref().unwrap().get(name) {
+                    Some(&id) => id,
+                    None => {
+                        connection.folders =
+                            Some(api!(get_folders(backend, store, user).await, command.tag));
+                        match connection.folders.as_ref().unwrap().get(name) {
+                            Some(&id) => id,
+                            None => {
+                                return vec![Response::Status(
+                                    Status::no(Some(command.tag), None, "No such mailbox!")
+                                        .unwrap(),
+                                )]
+                            }
+                        }
+                    }
+                };
+
+                let mut folder = api!(
+                    folder_info(backend, store, user, name, id).await,
+                    command.tag
+                );
+                let messages = mailbox::messages(name, &mut folder);
+
+                // Les nouveaux messages reçoivent leur UID dès maintenant pour que
+                // UIDNEXT change quand il en arrive. Pour le dossier sélectionné, on
+                // réutilise ses UIDs pour ne pas attribuer deux fois le même.
+                let selected = matches!(&connection.state, Selected(selected) if mailbox::name(selected) == name);
+                let mut opened;
+                let uids = match connection.uids.as_mut() {
+                    Some(uids) if selected => uids,
+                    _ => {
+                        opened = uids!(store, mailbox::key(name, id), command.tag);
+                        &mut opened
+                    }
+                };
+                uids.assign(&messages);
+
+                let items = mailbox::status(&messages, uids, item_names.as_ref());
+                return vec![
+                    Response::Data(Data::Status {
+                        mailbox,
+                        items: items.into(),
+                    }),
+                    Response::Status(
+                        Status::ok(Some(command.tag), None, "STATUS completed").unwrap(),
+                    ),
+                ];
+            }
+            _ => (),
+        }
+    }
+
+    if let Selected(mailbox) = &connection.state {
+        match command.body {
+            // Rien n'est gardé en attente : CHECK est un NOOP (RFC 3501 6.4.1)
+            Check => {
+                return vec![Response::Status(
+                    Status::ok(Some(command.tag), None, "CHECK completed").unwrap(),
+                )]
+            }
+            Close => {
+                connection.state = State::Authenticated;
+                return vec![Response::Status(
+                    Status::ok(Some(command.tag), None, "Mailbox closed").unwrap(),
+                )];
+            }
+            Search {
+                charset,
+                criteria,
+                uid,
+            } => {
+                if !search::supported(&charset) {
+                    return vec![Response::Status(
+                        Status::no(
+                            Some(command.tag),
+                            Some(Code::BadCharset {
+                                allowed: search::CHARSETS
+                                    .iter()
+                                    .map(|charset| Charset::try_from(*charset).unwrap())
+                                    .collect(),
+                            }),
+                            "Unsupported charset",
+                        )
+                        .unwrap(),
+                    )];
+                }
+
+                // unwrap: on est en selected
+                let user = connection.user.as_ref().unwrap();
+                let store = connection.store.as_ref().unwrap();
+                let uids = connection.uids.as_ref().unwrap();
+                let name = &*mailbox::name(mailbox);
+
+                let message_uids: Vec<_> = connection
+                    .messages
+                    .iter()
+                    .map(|message| uids.get(message.id).unwrap())
+                    .collect();
+                let largest = connection.messages.len() as u32;
+                let largest_uid = message_uids.last().map_or(0, |uid| uid.get());
+
+                let mut found = vec![];
+                let needs_raw = search::needs_raw(&criteria);
+                for (index, message) in connection.messages.iter().enumerate() {
+                    let raw = if needs_raw {
+                        Some(api!(
+                            raw_message(backend, store, user, name, message.id, message.read).await,
+                            command.tag
+                        ))
+                    } else {
+                        None
+                    };
+                    let candidate = search::Candidate {
+                        message,
+                        number: index as u32 + 1,
+                        uid: message_uids[index],
+                        largest,
+                        largest_uid,
+                        raw,
+                    };
+                    if search::matches(&criteria, &candidate) {
+                        found.push(if uid {
+                            message_uids[index]
+                        } else {
+                            // unwrap: index + 1 > 0
+                            NonZeroU32::new(index as u32 + 1).unwrap()
+                        });
+                    }
+                }
+
+                return vec![
+                    Response::Data(Data::Search(found)),
+                    Response::Status(
+                        Status::ok(Some(command.tag), None, "SEARCH completed").unwrap(),
+                    ),
+                ];
+            }
+            CommandBody::Store {
+                sequence_set,
+                kind,
+                response: store_response,
+                flags,
+                uid,
+            } => {
+                if connection.read_only {
+                    return vec![Response::Status(
+                        Status::no(Some(command.tag), None, "Mailbox is read-only").unwrap(),
+                    )];
+                }
+                if let Some(flag) = flags.iter().find(|flag| !message::storable(flag)) {
+                    return vec![Response::Status(
+                        Status::no(
+                            Some(command.tag),
+                            Some(code("CANNOT")),
+                            format!("{} cannot be stored on EcoleDirecte", flag),
+                        )
+                        .unwrap(),
+                    )];
+                }
+
+                // unwrap: on est en selected
+                let user = connection.user.as_ref().unwrap();
+                let store = connection.store.as_ref().unwrap();
+                let uids = connection.uids.as_ref().unwrap();
+                let name = &*mailbox::name(mailbox);
+
+                let message_uids: Vec<_> = connection
+                    .messages
+                    .iter()
+                    .map(|message| uids.get(message.id).unwrap())
+                    .collect();
+                let indices = if uid {
+                    mailbox::expand_uids(&sequence_set, &message_uids)
+                } else {
+                    mailbox::expand(&sequence_set, connection.messages.len() as u32)
+                };
+
+                // Pour revenir en arrière si l'API refuse le changement
+                let previous: Vec<_> = indices
+                    .iter()
+                    .map(|&index| {
+                        let message = &connection.messages[index];
+                        (index, message.read, message.local_flags.clone())
+                    })
+                    .collect();
+                // Messages dont l'état lu / non lu change
+                let (mut read, mut unread) = (vec![], vec![]);
+                for &index in &indices {
+                    let message = &mut connection.messages[index];
+                    let (id, seen) = (message.id, message.read);
+
+                    message::store_flags(message, &kind, &flags);
+
+                    match (seen, message.read) {
+                        (false, true) => read.push(id),
+                        (true, false) => unread.push(id),
+                        _ => (),
+                    }
+                }
+
+                // Chaque appel à l'API réussit ou échoue pour tous ses messages
+                let (mut refused, mut failed) = (vec![], None);
+                for (ids, state) in [(&read, true), (&unread, false)] {
+                    if !ids.is_empty() {
+                        if let Err(error) = backend.mark_read(user, ids, state).await {
+                            refused.extend_from_slice(ids);
+                            failed = Some(error);
+                        }
+                    }
+                }
+                if !read.is_empty() || !unread.is_empty() {
+                    if let Some(&id) = connection.folders.as_ref().and_then(|f| f.get(name)) {
+                        store.forget_folder(&mailbox::key(name, id));
+                    }
+                }
+                // Rien n'est enregistré pour les messages refusés : leur état
+                // local doit rester celui d'EcoleDirecte
+                for (index, read, local_flags) in previous {
+                    let message = &mut connection.messages[index];
+                    if refused.contains(&message.id) {
+                        message.read = read;
+                        message.local_flags = local_flags;
+                    }
+                }
+                let accepted: Vec<_> = indices
+                    .into_iter()
+                    .filter(|&index| !refused.contains(&connection.messages[index].id))
+                    .collect();
+
+                store.update_flags(|local_flags| {
+                    for &index in &accepted {
+                        let message = &connection.messages[index];
+                        if message.local_flags.is_empty() {
+                            local_flags.remove(&message.id);
+                        } else {
+                            local_flags.insert(message.id, message.local_flags.clone());
+                        }
+                    }
+                });
+
+                // Après un échec, le client doit apprendre ce qui a changé malgré tout
+                let mut response = vec![];
+                if store_response == StoreResponse::Answer || failed.is_some() {
+                    for index in accepted {
+                        let message = &connection.messages[index];
+                        let mut items = vec![];
+                        // UID STORE renvoie toujours l'UID
+                        if uid {
+                            items.push(MessageDataItem::Uid(message_uids[index]));
+                        }
+                        items.extend(message::item(message, None, &MessageDataItemName::Flags));
+                        if let Ok(data) = Data::fetch(index as u32 + 1, items) {
+                            response.push(Response::Data(data));
+                        }
+                    }
+                }
+                response.push(match failed {
+                    Some(error) => failure(command.tag, &error),
+                    None => Response::Status(
+                        Status::ok(Some(command.tag), None, "STORE completed").unwrap(),
+                    ),
+                });
+                return response;
+            }
+            Move {
+                sequence_set,
+                mailbox: destination,
+                uid,
+            } => {
+                if connection.read_only {
+                    return vec![Response::Status(
+                        Status::no(Some(command.tag), None, "Mailbox is read-only").unwrap(),
+                    )];
+                }
+                // unwrap: on est en selected
+                let user = connection.user.as_ref().unwrap();
+                let store = connection.store.as_ref().unwrap();
+                let name = &*mailbox::name(mailbox);
+                let destination = &*mailbox::name(&destination);
+
+                if !connection
+                    .folders
+                    .as_ref()
+                    .is_some_and(|folders| folders.contains_key(destination))
+                {
+                    connection.folders =
+                        Some(api!(get_folders(backend, store, user).await, command.tag));
+                }
+                // unwrap: on vient de remplir la liste des dossiers
+                let folders = connection.folders.as_ref().unwrap();
+                let Some(&destination_id) = folders.get(destination) else {
+                    return vec![Response::Status(
+                        Status::no(Some(command.tag), Some(Code::TryCreate), "No such mailbox!")
+                            .unwrap(),
+                    )];
+                };
+                let Some(action) = mailbox::move_action(name, destination) else {
+                    return vec![Response::Status(
+                        Status::no(
+                            Some(command.tag),
+                            Some(code("CANNOT")),
+                            // Le texte d'une réponse est en ASCII, comme les noms envoyés
+                            format!(
+                                "Cannot move messages from {} to {}",
+                                mailbox::encode(name),
+                                mailbox::encode(destination)
+                            ),
+                        )
+                        .unwrap(),
+                    )];
+                };
+
+                let uids = connection.uids.as_ref().unwrap();
+                let message_uids: Vec<_> = connection
+                    .messages
+                    .iter()
+                    .map(|message| uids.get(message.id).unwrap())
+                    .collect();
+                let indices = if uid {
+                    mailbox::expand_uids(&sequence_set, &message_uids)
+                } else {
+                    mailbox::expand(&sequence_set, connection.messages.len() as u32)
+                };
+                if indices.is_empty() {
+                    return vec![Response::Status(
+                        Status::ok(Some(command.tag), None, "No messages to move").unwrap(),
+                    )];
+                }
+
+                let ids: Vec<_> = indices
+                    .iter()
+                    .map(|&index| connection.messages[index].id)
+                    .collect();
+                api!(
+                    backend
+                        .move_messages(user, &ids, action, destination_id)
+                        .await,
+                    command.tag
+                );
+                // Le dossier sélectionné a pu être supprimé entre temps
+                if let Some(&id) = folders.get(name) {
+                    store.forget_folder(&mailbox::key(name, id));
+                }
+                store.forget_folder(&mailbox::key(destination, destination_id));
+
+                // Les messages sont déjà déplacés : sans les UIDs de la
+                // destination, on répond simplement sans COPYUID
+                let key = mailbox::key(destination, destination_id);
+                let mut response = vec![];
+                match store.uids(&key) {
+                    Ok(mut destination_uids) => {
+                        let new_uids = destination_uids.renew(&ids);
+                        let source_uids: Vec<_> =
+                            indices.iter().map(|&index| message_uids[index]).collect();
+                        response.push(Response::Status(
+                            Status::ok(
+                                None,
+                                Some(code(format!(
+                                    "COPYUID {} {} {}",
+                                    destination_uids.validity,
+                                    mailbox::uid_set(&source_uids),
+                                    mailbox::uid_set(&new_uids),
+                                ))),
+                                "Moved",
+                            )
+                            .unwrap(),
+                        ));
+                    }
+                    Err(error) => log::error!("Impossible de lire les UIDs de {}: {}", key, error),
+                }
+                // Du plus grand au plus petit pour que les numéros restent valables
+                for &index in indices.iter().rev() {
+                    connection.messages.remove(index);
+                    // unwrap: index + 1 > 0
+                    response.push(Response::Data(Data::Expunge(
+                        NonZeroU32::new(index as u32 + 1).unwrap(),
+                    )));
+                }
+                response.push(Response::Status(
+                    Status::ok(Some(command.tag), None, "MOVE completed").unwrap(),
+                ));
+                return response;
+            }
+            Fetch {
+                sequence_set,
+                macro_or_item_names,
+                uid,
+            } => {
+                let mut item_names = match &macro_or_item_names {
+                    MacroOrMessageDataItemNames::Macro(m) => m.expand(),
+                    MacroOrMessageDataItemNames::MessageDataItemNames(names) => names.clone(),
+                };
+                // UID FETCH renvoie toujours l'UID
+                if uid && !item_names.contains(&MessageDataItemName::Uid) {
+                    item_names.insert(0, MessageDataItemName::Uid);
+                }
+                // unwrap: on est en selected
+                let user = connection.user.as_ref().unwrap();
+                let store = connection.store.as_ref().unwrap();
+                let name = &*mailbox::name(mailbox);
+                let needs_raw = item_names.iter().any(message::needs_raw);
+                // En lecture seule, BODY[] se comporte comme BODY.PEEK[]
+                let sets_seen = !connection.read_only
+                    && item_names.iter().any(|name| {
+                        matches!(
+                            name,
+                            MessageDataItemName::BodyExt { peek: false, .. }
+                                | MessageDataItemName::Rfc822
+                                | MessageDataItemName::Rfc822Text
+                        )
+                    });
+
+                // unwrap: les UIDs sont attribués à la sélection du dossier
+                let uids = connection.uids.as_ref().unwrap();
+                let message_uids: Vec<_> = connection
+                    .messages
+                    .iter()
+                    .map(|message| uids.get(message.id).unwrap())
+                    .collect();
+                let indices = if uid {
+                    mailbox::expand_uids(&sequence_set, &message_uids)
+                } else {
+                    mailbox::expand(&sequence_set, connection.messages.len() as u32)
+                };
+
+                let mut response = vec![];
+                let mut newly_seen = vec![];
+                let mut error = None;
+                for index in indices {
+                    let message = &mut connection.messages[index];
+                    let (id, seen) = (message.id, message.read);
+
+                    let raw = if needs_raw {
+                        match raw_message(backend, store, user, name, id, seen || sets_seen).await {
+                            Ok(raw) => Some(raw),
+                            Err(failed) => {
+                                error = Some(failed);
+                                break;
+                            }
+                        }
+                    } else {
+                        None
+                    };
+
+                    if sets_seen && !seen {
+                        message.read = true;
+                        newly_seen.push(id);
+                    }
+
+                    // Les éléments pas encore gérés sont ignorés
+                    let mut items: Vec<_> = item_names
+                        .iter()
+                        .filter_map(|name| match name {
+                            MessageDataItemName::Uid => {
+                                Some(MessageDataItem::Uid(message_uids[index]))
+                            }
+                            _ => message::item(message, raw.as_deref(), name),
+                        })
+                        .collect();
+                    if sets_seen && !seen && !item_names.contains(&MessageDataItemName::Flags) {
+                        items.extend(message::item(message, None, &MessageDataItemName::Flags));
+                    }
+
+                    if let Ok(data) = Data::fetch(index as u32 + 1, items) {
+                        response.push(Response::Data(data));
+                    }
+                }
+
+                // Le message a pu venir du cache, sans passer par l'API
+                if !newly_seen.is_empty() {
+                    if let Err(error) = backend.mark_read(user, &newly_seen, true).await {
+                        log::warn!("Erreur de l'API : {}", error);
+                    }
+                    if let Some(&id) = connection.folders.as_ref().and_then(|f| f.get(name)) {
+                        store.forget_folder(&mailbox::key(name, id));
+                    }
+                }
+
+                // Les messages déjà envoyés restent valables
+                response.push(match error {
+                    Some(error) => failure(command.tag, &error),
+                    None => Response::Status(
+                        Status::ok(Some(command.tag), None, "FETCH completed").unwrap(),
+                    ),
+                });
+                return response;
+            }
+            _ => (),
+        }
+    }
+
+    vec![Response::Status(
+        Status::no(Some(command.tag), None, "Not supported!").unwrap(),
+    )]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::fake::{Fake, PASSWORD, USERNAME};
+    use crate::common::{self, Client};
+    use std::sync::Once;
+
+    fn contains(lines: &[String], expected: &str) -> bool {
+        lines.iter().any(|line| line.trim_end() == expected)
+    }
+
+    // Les tests tournent avec le dossier des messages envoyés traduit, pour
+    // vérifier que son rôle ne dépend pas de son nom. La configuration n'est
+    // fixée qu'une fois, avant que les tests ne la lisent.
+    fn configure() {
+        static INIT: Once = Once::new();
+        INIT.call_once(|| {
+            common::environment();
+            let mut config = config::Config::default();
+            config.apply_env().unwrap();
+            config.folders.sent = String::from("Envoyés");
+            config::set(config);
+        });
+    }
+
+    // Fait tourner le serveur sur une connexion locale, avec `fake` comme messagerie
+    fn session(fake: &Fake, test: impl FnOnce(&mut Client)) {
+        configure();
+        let server = |socket| responder(Stream::from(socket), Connection::default(), fake);
+        common::serve((USERNAME, PASSWORD), None, server, test);
+    }
+
+    #[test]
+    fn login_is_required() {
+        let fake = Fake::new(1);
+        session(&fake, |client| {
+            assert!(client.status("SELECT INBOX").starts_with("NO"));
+            let status = client.status(&format!("LOGIN {} wrong", USERNAME));
+            assert!(
+                status.starts_with("NO [AUTHENTICATIONFAILED]"),
+                "{}",
+                status
+            );
+            client.login();
+        });
+    }
+
+    #[test]
+    fn list_shows_system_folders_and_classeurs() {
+        let fake = Fake::new(2);
+        fake.add_classeur(3, "Cours/Maths");
+        session(&fake, |client| {
+            client.login();
+            let lines = client.command("LIST \"\" \"*\"");
+            assert!(contains(&lines, "* LIST () \"/\" INBOX"), "{:?}", lines);
+            assert!(
+                contains(&lines, "* LIST (\\Sent) \"/\" Envoy&AOk-s"),
+                "{:?}",
+                lines
+            );
+            assert!(
+                contains(&lines, "* LIST (\\Noselect) \"/\" Cours"),
+                "{:?}",
+                lines
+            );
+            assert!(
+                contains(&lines, "* LIST () \"/\" Cours/Maths"),
+                "{:?}",
+                lines
+            );
+        });
+    }
+
+    #[test]
+    fn select_and_fetch_flags() {
+        let fake = Fake::new(3);
+        fake.receive(1, "Sortie scolaire", true);
+        fake.receive(2, "Devoirs de maths", false);
+        session(&fake, |client| {
+            client.login();
+            let lines = client.command("SELECT INBOX");
+            assert!(contains(&lines, "* 2 EXISTS"), "{:?}", lines);
+            assert!(lines.last().unwrap().contains("[READ-WRITE]"));
+
+            let lines = client.command("FETCH 1:* (FLAGS)");
+            assert!(
+                contains(&lines, "* 1 FETCH (FLAGS (\\Seen))"),
+                "{:?}",
+                lines
+            );
+            assert!(contains(&lines, "* 2 FETCH (FLAGS ())"), "{:?}", lines);
+        });
+    }
+
+    #[test]
+    fn dates_are_paris_time() {
+        let fake = Fake::new(15);
+        // 2023-10-02 10:00:00, à l'heure d'été de Paris
+        fake.receive(1, "Sortie scolaire", true);
+        session(&fake, |client| {
+            client.login();
+            client.command("SELECT INBOX");
+            let lines = client.command("FETCH 1 (INTERNALDATE)");
+            assert!(
+                contains(
+                    &lines,
+                    "* 1 FETCH (INTERNALDATE \"02-Oct-2023 10:00:00 +0200\")"
+                ),
+                "{:?}",
+                lines
+            );
+        });
+    }
+
+    #[test]
+    fn counters_follow_sequence_numbers() {
+        let fake = Fake::new(14);
+        fake.receive(1, "Sortie scolaire", true);
+        fake.receive(2, "Réunion parents-professeurs", true);
+        fake.receive(3, "Devoirs de maths", false);
+        session(&fake, |client| {
+            client.login();
+            // Un seul message non lu, le troisième
+            let lines = client.command("SELECT INBOX");
+            assert!(contains(&lines, "* 3 EXISTS"), "{:?}", lines);
+            assert!(
+                lines.iter().any(|line| line.starts_with("* OK [UNSEEN 3]")),
+                "{:?}",
+                lines
+            );
+            let lines = client.command("FETCH 1:* (FLAGS)");
+            assert_eq!(lines.len(), 4, "{:?}", lines);
+
+            let lines = client.command("STATUS INBOX (MESSAGES UNSEEN)");
+            assert!(
+                contains(&lines, "* STATUS INBOX (MESSAGES 3 UNSEEN 1)"),
+                "{:?}",
+                lines
+            );
+
+            // Tout est lu : pas d'UNSEEN
+            client.status("STORE 3 +FLAGS (\\Seen)");
+            let lines = client.command("SELECT INBOX");
+            assert!(
+                !lines.iter().any(|line| line.contains("[UNSEEN")),
+                "{:?}",
+                lines
+            );
+        });
+    }
+
+    #[test]
+    fn store_reaches_ecoledirecte() {
+        let fake = Fake::new(4);
+        fake.receive(1, "Sortie scolaire", true);
+        fake.receive(2, "Devoirs de maths", false);
+        session(&fake, |client| {
+            client.login();
+            client.command("SELECT INBOX");
+
+            assert!(client.status("STORE 2 +FLAGS (\\Seen)").starts_with("OK"));
+            assert!(fake.is_read(2));
+            assert!(client
+                .status("STORE 1 -FLAGS.SILENT (\\Seen)")
+                .starts_with("OK"));
+            assert!(!fake.is_read(1));
+
+            // \Flagged n'existe pas sur EcoleDirecte, il est conservé localement
+            let lines = client.command("STORE 1 +FLAGS (\\Flagged)");
+            assert!(
+                contains(&lines, "* 1 FETCH (FLAGS (\\Flagged))"),
+                "{:?}",
+                lines
+            );
+            let status = client.status("STORE 1 +FLAGS (\\Deleted)");
+            assert!(status.starts_with("NO [CANNOT]"), "{}", status);
+        });
+    }
+
+    #[test]
+    fn answered_can_be_removed() {
+        let fake = Fake::new(19);
+        fake.receive(1, "Sortie scolaire", true);
+        fake.answer(1);
+        session(&fake, |client| {
+            client.login();
+            client.command("SELECT INBOX");
+            let lines = client.command("FETCH 1 (FLAGS)");
+            assert!(
+                contains(&lines, "* 1 FETCH (FLAGS (\\Seen \\Answered))"),
+                "{:?}",
+                lines
+            );
+
+            client.status("STORE 1 -FLAGS.SILENT (\\Answered)");
+            client.command("SELECT INBOX");
+            let lines = client.command("FETCH 1 (FLAGS)");
+            assert!(
+                contains(&lines, "* 1 FETCH (FLAGS (\\Seen))"),
+                "{:?}",
+                lines
+            );
+        });
+    }
+
+    #[test]
+    fn failed_store_keeps_local_flags() {
+        let fake = Fake::new(20);
+        fake.receive(1, "Sortie scolaire", false);
+        session(&fake, |client| {
+            client.login();
+            client.command("SELECT INBOX");
+            fake.set_down(true);
+            let status = client.status("STORE 1 +FLAGS (\\Seen \\Flagged)");
+            assert!(status.starts_with("NO"), "{}", status);
+
+            fake.set_down(false);
+            client.command("SELECT INBOX");
+            let lines = client.command("FETCH 1 (FLAGS)");
+            assert!(contains(&lines, "* 1 FETCH (FLAGS ())"), "{:?}", lines);
+        });
+    }
+
+    #[test]
+    fn examine_does_not_mark_read() {
+        let fake = Fake::new(5);
+        fake.receive(1, "Devoirs de maths", false);
+        session(&fake, |client| {
+            client.login();
+            let lines = client.command("EXAMINE INBOX");
+            assert!(lines.last().unwrap().contains("[READ-ONLY]"));
+
+            let lines = client.command("FETCH 1 (BODY[])");
+            assert!(lines.last().unwrap().contains("OK"), "{:?}", lines);
+            assert!(lines.iter().any(|line| line.contains("Devoirs de maths")));
+            assert!(!fake.is_read(1));
+            assert!(client.status("STORE 1 +FLAGS (\\Seen)").starts_with("NO"));
+        });
+    }
+
+    #[test]
+    fn search_by_flag_and_subject() {
+        let fake = Fake::new(6);
+        fake.receive(1, "Sortie scolaire", true);
+        fake.receive(2, "Devoirs de maths", false);
+        session(&fake, |client| {
+            client.login();
+            client.command("SELECT INBOX");
+            assert!(contains(&client.command("SEARCH UNSEEN"), "* SEARCH 2"));
+            assert!(contains(
+                &client.command("SEARCH SUBJECT sortie"),
+                "* SEARCH 1"
+            ));
+        });
+    }
+
+    #[test]
+    fn move_to_archive() {
+        let fake = Fake::new(7);
+        fake.receive(1, "Sortie scolaire", true);
+        fake.receive(2, "Devoirs de maths", false);
+        session(&fake, |client| {
+            client.login();
+            client.command("SELECT INBOX");
+            let lines = client.command("MOVE 1 Archived");
+            assert!(lines[0].starts_with("* OK [COPYUID "), "{:?}", lines);
+            assert!(contains(&lines, "* 1 EXPUNGE"), "{:?}", lines);
+            assert_eq!(fake.location(1), Some(("archived", 0)));
+            assert_eq!(fake.location(2), Some(("received", 0)));
+
+            let status = client.status("MOVE 1 Envoy&AOk-s");
+            assert!(status.starts_with("NO [CANNOT]"), "{}", status);
+        });
+    }
+
+    #[test]
+    fn create_rename_delete_classeurs() {
+        let fake = Fake::new(8);
+        session(&fake, |client| {
+            client.login();
+            assert!(client.status("CREATE Projets").starts_with("OK"));
+            assert_eq!(fake.classeurs(), ["Projets"]);
+            assert!(client.status("RENAME Projets Devoirs").starts_with("OK"));
+            assert_eq!(fake.classeurs(), ["Devoirs"]);
+            assert!(client.status("DELETE Devoirs").starts_with("OK"));
+            assert!(fake.classeurs().is_empty());
+
+            // Supprimer le dossier sélectionné le désélectionne
+            assert!(client.status("CREATE Projets").starts_with("OK"));
+            assert!(client.status("SELECT Projets").starts_with("OK"));
+            assert!(client.status("DELETE Projets").starts_with("OK"));
+            let status = client.status("FETCH 1:* (FLAGS)");
+            assert!(
+                status.starts_with("BAD") || status.starts_with("NO"),
+                "{}",
+                status
+            );
+
+            let status = client.status("CREATE INBOX");
+            assert!(status.starts_with("NO [CANNOT]"), "{}", status);
+            let status = client.status("DELETE Devoirs");
+            assert!(status.starts_with("NO [NONEXISTENT]"), "{}", status);
+        });
+    }
+
+    #[test]
+    fn accented_names_are_modified_utf7() {
+        let fake = Fake::new(17);
+        fake.add_classeur(4, "Année 2023");
+        session(&fake, |client| {
+            client.login();
+            let lines = client.command("LIST \"\" \"Ann*\"");
+            assert!(
+                contains(&lines, "* LIST () \"/\" \"Ann&AOk-e 2023\""),
+                "{:?}",
+                lines
+            );
+            assert!(client.status("SELECT \"Ann&AOk-e 2023\"").starts_with("OK"));
+
+            assert!(client.status("CREATE R&AOk-sum&AOk-s").starts_with("OK"));
+            assert!(fake.classeurs().contains(&String::from("Résumés")));
+
+            let status = client.status("SELECT Ann&AOk");
+            assert!(status.starts_with("BAD"), "{}", status);
+        });
+    }
+
+    #[test]
+    fn api_failure_is_reported() {
+        let fake = Fake::new(9);
+        fake.receive(1, "Sortie scolaire", true);
+        session(&fake, |client| {
+            client.login();
+            fake.set_down(true);
+            let status = client.status("SELECT INBOX");
+            assert!(status.starts_with("NO [UNAVAILABLE]"), "{}", status);
+
+            // La connexion continue de fonctionner
+            fake.set_down(false);
+            assert!(contains(&client.command("SELECT INBOX"), "* 1 EXISTS"));
+        });
+    }
+
+    #[test]
+    fn login_with_literals() {
+        let fake = Fake::new(10);
+        session(&fake, |client| {
+            let lines = client.command("CAPABILITY");
+            assert!(lines[0].contains(" LITERAL+"), "{:?}", lines);
+
+            // Nom d'utilisateur en littéral synchrone, mot de passe en LITERAL+
+            client.write(format!("A LOGIN {{{}}}\r\n", USERNAME.len()));
+            assert!(client.line().starts_with("+ "));
+            client.write(format!(
+                "{} {{{}+}}\r\n{}\r\n",
+                USERNAME,
+                PASSWORD.len(),
+                PASSWORD
+            ));
+            assert!(client.line().starts_with("A OK"));
+            assert!(client.status("SELECT INBOX").starts_with("OK"));
+        });
+    }
+
+    #[test]
+    fn literal_too_big() {
+        let fake = Fake::new(11);
+        session(&fake, |client| {
+            // Le client n'envoie pas le littéral puisqu'on le refuse
+            client.write(format!("A LOGIN {} {{100000}}\r\n", USERNAME));
+            let line = client.line();
+            assert!(line.starts_with("A NO [TOOBIG]"), "{}", line);
+
+            // Celui-ci est envoyé d'office et doit être ignoré en entier
+            client.write(format!(
+                "B LOGIN {} {{100000+}}\r\n{}\r\n",
+                USERNAME,
+                "x".repeat(100000)
+            ));
+            let line = client.line();
+            assert!(line.starts_with("B BAD [TOOBIG]"), "{}", line);
+
+            client.login();
+        });
+    }
+
+    #[test]
+    fn chained_literals_too_big() {
+        let fake = Fake::new(18);
+        session(&fake, |client| {
+            // Chaque littéral est accepté, mais pas la commande entière
+            let literal = format!("{{65536+}}\r\n{}", "x".repeat(65536));
+            let criteria = vec![format!("TEXT {}", literal); 5].join(" ");
+            client.write(format!("A SEARCH {}\r\n", criteria));
+            let line = client.line();
+            assert!(line.starts_with("A BAD [TOOBIG]"), "{}", line);
+
+            client.login();
+        });
+    }
+
+    #[test]
+    fn eight_bit_literals() {
+        let fake = Fake::new(16);
+        session(&fake, |client| {
+            client.write(b"A LOGIN {2+}\r\n\xff\xfe {2+}\r\n\xff\xfe\r\n");
+            let line = client.line();
+            assert!(line.starts_with("A NO"), "{}", line);
+
+            client.login();
+            client.write(b"B SELECT {2+}\r\n\xff\xfe\r\n");
+            let line = client.line();
+            assert!(line.starts_with("B BAD"), "{}", line);
+
+            // La connexion continue de fonctionner
+            assert!(client.status("SELECT INBOX").starts_with("OK"));
+        });
+    }
+
+    #[test]
+    fn command_too_long() {
+        let fake = Fake::new(12);
+        session(&fake, |client| {
+            let uids: Vec<String> = (1..60000).map(|uid| uid.to_string()).collect();
+            let status = client.status(&format!("UID FETCH {} FLAGS", uids.join(",")));
+            assert_eq!(status, "BAD Command too long");
+
+            client.write("B AUTHENTICATE PLAIN\r\n");
+            assert!(client.line().starts_with("+"));
+            client.write(format!("{}\r\n", "A".repeat(300000)));
+            let line = client.line();
+            assert!(line.starts_with("B BAD"), "{}", line);
+
+            client.login();
+        });
+    }
+
+    #[test]
+    fn authenticate_data_sent_with_the_command() {
+        use base64::{engine::general_purpose::STANDARD as base64, Engine};
+
+        let fake = Fake::new(13);
+        session(&fake, |client| {
+            // La commande, ses données et la commande suivante en un seul envoi
+            let data = base64.encode(format!("\0{}\0{}", USERNAME, PASSWORD));
+            client.write(format!(
+                "A AUTHENTICATE PLAIN\r\n{}\r\nB SELECT INBOX\r\n",
+                data
+            ));
+            assert!(client.line().starts_with("+"));
+            let line = client.line();
+            assert!(line.starts_with("A OK"), "{}", line);
+            loop {
+                let line = client.line();
+                assert!(!line.is_empty());
+                if line.starts_with("B ") {
+                    assert!(line.starts_with("B OK"), "{}", line);
+                    break;
+                }
+            }
+        });
+    }
+
+    #[test]
+    fn unreadable_uids_are_kept() {
+        let fake = Fake::new(21);
+        fake.receive(1, "Sortie scolaire", true);
+        session(&fake, |client| {
+            client.login();
+            assert!(client.status("SELECT INBOX").starts_with("OK"));
+
+            // Le fichier illisible ne doit pas être remplacé par un nouveau
+            let path = crate::data_dir()
+                .join("21/uids")
+                .join(mailbox::key("INBOX", 0));
+            std::fs::write(&path, "illisible\n1 1\n").unwrap();
+            let status = client.status("SELECT INBOX");
+            assert!(status.starts_with("NO [UNAVAILABLE]"), "{}", status);
+            assert_eq!(std::fs::read_to_string(&path).unwrap(), "illisible\n1 1\n");
+        });
+    }
+
+    #[test]
+    fn returning_message_gets_a_new_uid() {
+        let fake = Fake::new(22);
+        fake.receive(1, "Sortie scolaire", true);
+        fake.receive(2, "Devoirs de maths", true);
+        session(&fake, |client| {
+            client.login();
+            client.command("SELECT INBOX");
+            let lines = client.command("FETCH 2 (UID)");
+            assert!(contains(&lines, "* 2 FETCH (UID 2)"), "{:?}", lines);
+
+            // Archivé puis désarchivé depuis le site pendant IDLE
+            client.write("I IDLE\r\n");
+            assert!(client.line().starts_with("+ "));
+            fake.relocate(2, "archived", 0);
+            assert_eq!(client.line().trim_end(), "* 2 EXPUNGE");
+            fake.relocate(2, "received", 0);
+            assert_eq!(client.line().trim_end(), "* 2 EXISTS");
+            client.write("DONE\r\n");
+            assert!(client.line().starts_with("I OK"));
+
+            let lines = client.command("FETCH 2 (UID)");
+            assert!(contains(&lines, "* 2 FETCH (UID 3)"), "{:?}", lines);
+        });
+    }
+
+    #[test]
+    fn uids_persist_across_connections() {
+        let fake = Fake::new(23);
+        fake.receive(5, "Sortie scolaire", true);
+        fake.receive(3, "Devoirs de maths", false);
+        let mut validity = String::new();
+        session(&fake, |client| {
+            client.login();
+            // Les UIDs suivent l'ordre des identifiants : 3 puis 5
+            let lines = client.command("SELECT INBOX");
+            assert!(
+                contains(&lines, "* OK [UIDNEXT 3] Predicted next UID"),
+                "{:?}",
+                lines
+            );
+            validity = lines
+                .iter()
+                .find(|line| line.contains("[UIDVALIDITY"))
+                .unwrap()
+                .clone();
+
+            let lines = client.command("UID FETCH 2 (FLAGS)");
+            assert!(
+                contains(&lines, "* 2 FETCH (UID 2 FLAGS (\\Seen))"),
+                "{:?}",
+                lines
+            );
+            let lines = client.command("UID SEARCH UNSEEN");
+            assert!(contains(&lines, "* SEARCH 1"), "{:?}", lines);
+            let lines = client.command("UID SEARCH 2:*");
+            assert!(contains(&lines, "* SEARCH 2"), "{:?}", lines);
+        });
+
+        fake.receive(4, "Réunion parents-professeurs", false);
+        // La liste en cache ne connaît pas encore le nouveau message
+        std::fs::remove_dir_all(crate::data_dir().join("23/folders")).unwrap();
+        session(&fake, |client| {
+            client.login();
+            let lines = client.command("SELECT INBOX");
+            assert!(lines.contains(&validity), "{:?}", lines);
+            assert!(
+                contains(&lines, "* OK [UIDNEXT 4] Predicted next UID"),
+                "{:?}",
+                lines
+            );
+
+            // Le nouveau message vient après les autres
+            let lines = client.command("UID FETCH 1:* (UID)");
+            assert!(contains(&lines, "* 1 FETCH (UID 1)"), "{:?}", lines);
+            assert!(contains(&lines, "* 2 FETCH (UID 2)"), "{:?}", lines);
+            assert!(contains(&lines, "* 3 FETCH (UID 3)"), "{:?}", lines);
+            let lines = client.command("UID SEARCH UNSEEN");
+            assert!(contains(&lines, "* SEARCH 1 3"), "{:?}", lines);
+        });
+    }
+
+    #[test]
+    fn idle_reports_changes() {
+        let fake = Fake::new(24);
+        fake.receive(1, "Sortie scolaire", true);
+        fake.receive(2, "Devoirs de maths", false);
+        fake.receive(3, "Cantine", true);
+        session(&fake, |client| {
+            client.login();
+            client.command("SELECT INBOX");
+            client.write("I IDLE\r\n");
+            assert!(client.line().starts_with("+ "));
+
+            fake.receive(4, "Réunion parents-professeurs", false);
+            assert_eq!(client.line().trim_end(), "* 4 EXISTS");
+
+            // Les deux départs doivent arriver ensemble : du dernier au premier
+            fake.set_down(true);
+            fake.relocate(1, "archived", 0);
+            fake.relocate(3, "archived", 0);
+            fake.set_down(false);
+            assert_eq!(client.line().trim_end(), "* 3 EXPUNGE");
+            assert_eq!(client.line().trim_end(), "* 1 EXPUNGE");
+
+            fake.set_read(2, true);
+            assert_eq!(client.line().trim_end(), "* 1 FETCH (FLAGS (\\Seen))");
+
+            client.write("DONE\r\n");
+            assert_eq!(client.line().trim_end(), "I OK IDLE terminated");
+            let lines = client.command("FETCH 1:* (UID)");
+            assert_eq!(lines.len(), 3, "{:?}", lines);
+        });
+    }
+
+    #[test]
+    fn list_with_many_wildcards() {
+        let fake = Fake::new(25);
+        fake.add_classeur(3, "Cours/Maths");
+        session(&fake, |client| {
+            client.login();
+            // Sans retour en arrière, le motif ne bloque pas le serveur
+            let lines = client.command(&format!("LIST \"\" \"{}x\"", "*%".repeat(15)));
+            assert_eq!(lines.len(), 1, "{:?}", lines);
+            assert!(lines[0].starts_with("A2 OK"), "{:?}", lines);
+            let lines = client.command("LIST \"\" \"**C*s*%h*\"");
+            assert!(
+                contains(&lines, "* LIST () \"/\" Cours/Maths"),
+                "{:?}",
+                lines
+            );
+        });
+    }
+
+    #[test]
+    fn rename_moves_inferior_classeurs() {
+        let fake = Fake::new(26);
+        fake.add_classeur(3, "Cours");
+        fake.add_classeur(4, "Cours/Maths");
+        fake.add_classeur(5, "Courses");
+        fake.add_classeur(6, "Matières/Maths");
+        session(&fake, |client| {
+            client.login();
+            let status = client.status("RENAME Cours Mati&AOg-res");
+            assert!(status.starts_with("NO [ALREADYEXISTS]"), "{}", status);
+            assert!(client.status("DELETE Mati&AOg-res/Maths").starts_with("OK"));
+
+            assert!(client.status("SELECT Cours/Maths").starts_with("OK"));
+            assert!(client.status("RENAME Cours Mati&AOg-res").starts_with("OK"));
+            assert_eq!(fake.classeurs(), ["Matières", "Matières/Maths", "Courses"]);
+            let lines = client.command("LIST \"\" \"Cours*\"");
+            assert!(contains(&lines, "* LIST () \"/\" Courses"), "{:?}", lines);
+            assert_eq!(lines.len(), 2, "{:?}", lines);
+        });
+    }
+
+    #[test]
+    fn list_hierarchy() {
+        let fake = Fake::new(27);
+        fake.add_classeur(3, "Cours/Maths");
+        fake.add_classeur(4, "Cours/Maths/Exercices");
+        session(&fake, |client| {
+            client.login();
+            // "%" s'arrête au séparateur, le parent absent est \Noselect
+            let lines = client.command("LIST \"\" \"%\"");
+            assert!(
+                contains(&lines, "* LIST (\\Noselect) \"/\" Cours"),
+                "{:?}",
+                lines
+            );
+            assert!(
+                !contains(&lines, "* LIST () \"/\" Cours/Maths"),
+                "{:?}",
+                lines
+            );
+            let lines = client.command("LIST \"\" \"Cours/%\"");
+            assert_eq!(lines.len(), 2, "{:?}", lines);
+            assert!(
+                contains(&lines, "* LIST () \"/\" Cours/Maths"),
+                "{:?}",
+                lines
+            );
+
+            // La référence est ajoutée devant le motif
+            let lines = client.command("LIST \"Cours/\" \"*\"");
+            assert_eq!(lines.len(), 3, "{:?}", lines);
+            assert!(
+                contains(&lines, "* LIST () \"/\" Cours/Maths"),
+                "{:?}",
+                lines
+            );
+            assert!(
+                contains(&lines, "* LIST () \"/\" Cours/Maths/Exercices"),
+                "{:?}",
+                lines
+            );
+        });
+    }
+
+    #[test]
+    fn subscriptions_persist() {
+        let fake = Fake::new(28);
+        fake.add_classeur(3, "Cours/Maths");
+        fake.add_classeur(4, "Projets");
+        session(&fake, |client| {
+            client.login();
+            // Sans abonnement enregistré, le client est abonné à tout
+            let lines = client.command("LSUB \"\" \"*\"");
+            assert!(contains(&lines, "* LSUB () \"/\" Projets"), "{:?}", lines);
+            assert!(client.status("UNSUBSCRIBE Projets").starts_with("OK"));
+        });
+        session(&fake, |client| {
+            client.login();
+            let lines = client.command("LSUB \"\" \"*\"");
+            assert!(!contains(&lines, "* LSUB () \"/\" Projets"), "{:?}", lines);
+            assert!(
+                contains(&lines, "* LSUB () \"/\" Cours/Maths"),
+                "{:?}",
+                lines
+            );
+            assert!(client.status("SUBSCRIBE Projets").starts_with("OK"));
+        });
+        session(&fake, |client| {
+            client.login();
+            let lines = client.command("LSUB \"\" \"*\"");
+            assert!(contains(&lines, "* LSUB () \"/\" Projets"), "{:?}", lines);
+        });
+    }
+
+    #[test]
+    fn concurrent_stores_keep_each_other_flags() {
+        let fake = Fake::new(29);
+        for id in 1..=20 {
+            fake.receive(id, "Sortie scolaire", true);
+        }
+        // Deux connexions au même compte marquent chacune la moitié des messages
+        std::thread::scope(|s| {
+            for first in [1, 2] {
+                let fake = &fake;
+                s.spawn(move || {
+                    session(fake, |client| {
+                        client.login();
+                        client.command("SELECT INBOX");
+                        for number in (first..=20).step_by(2) {
+                            let status =
+                                client.status(&format!("STORE {} +FLAGS (\\Flagged)", number));
+                            assert!(status.starts_with("OK"), "{}", status);
+                        }
+                    });
+                });
+            }
+        });
+        session(&fake, |client| {
+            client.login();
+            client.command("SELECT INBOX");
+            let lines = client.command("SEARCH FLAGGED");
+            let all: Vec<_> = (1..=20).map(|number| number.to_string()).collect();
+            assert!(
+                contains(&lines, &format!("* SEARCH {}", all.join(" "))),
+                "{:?}",
+                lines
+            );
+        });
+    }
+
+    #[test]
+    fn partly_failed_store_keeps_accepted_flags() {
+        let fake = Fake::new(30);
+        fake.receive(1, "Sortie scolaire", true);
+        fake.receive(2, "Devoirs de maths", false);
+        fake.lock_message(1);
+        session(&fake, |client| {
+            client.login();
+            client.command("SELECT INBOX");
+            // Le 1 devrait redevenir non lu, ce que l'API refuse ; le 2 ne
+            // change que localement
+            let lines = client.command("STORE 1:2 FLAGS.SILENT (\\Flagged)");
+            assert!(lines.last().unwrap().contains(" NO "), "{:?}", lines);
+            assert!(
+                contains(&lines, "* 2 FETCH (FLAGS (\\Flagged))"),
+                "{:?}",
+                lines
+            );
+
+            client.command("SELECT INBOX");
+            let lines = client.command("FETCH 1:2 (FLAGS)");
+            assert!(
+                contains(&lines, "* 1 FETCH (FLAGS (\\Seen))"),
+                "{:?}",
+                lines
+            );
+            assert!(
+                contains(&lines, "* 2 FETCH (FLAGS (\\Flagged))"),
+                "{:?}",
+                lines
+            );
+        });
+    }
+
+    #[test]
+    fn credentials_are_not_logged() {
+        use imap_codec::{decode::Decoder, CommandCodec};
+
+        for line in [
+            "A LOGIN eleve secret\r\n",
+            "A LOGIN eleve {6+}\r\nsecret\r\n",
+            "A AUTHENTICATE PLAIN AGVsZXZlAHNlY3JldA==\r\n",
+        ] {
+            let (_, command) = CommandCodec::default().decode(line.as_bytes()).unwrap();
+            let logged = loggable(&command);
+            assert!(!logged.contains("secret"), "{}", logged);
+            assert!(!logged.contains("AGVsZXZlAHNlY3JldA"), "{}", logged);
+        }
+        let (_, command) = CommandCodec::default()
+            .decode(b"A SELECT INBOX\r\n")
+            .unwrap();
+        assert_eq!(loggable(&command), "A SELECT INBOX");
+    }
+
+    #[test]
+    fn list_special_use() {
+        let fake = Fake::new(31);
+        fake.add_classeur(3, "Projets");
+        session(&fake, |client| {
+            let lines = client.command("CAPABILITY");
+            assert!(lines[0].contains(" SPECIAL-USE"), "{:?}", lines);
+            client.login();
+
+            // Seulement les dossiers qui ont un rôle, même traduits
+            let lines = client.command("list (special-use) \"\" \"*\"");
+            assert_eq!(lines.len(), 4, "{:?}", lines);
+            assert!(
+                contains(&lines, "* LIST (\\Sent) \"/\" Envoy&AOk-s"),
+                "{:?}",
+                lines
+            );
+            assert!(
+                contains(&lines, "* LIST (\\Archive) \"/\" Archived"),
+                "{:?}",
+                lines
+            );
+            assert!(
+                contains(&lines, "* LIST (\\Drafts) \"/\" Drafts"),
+                "{:?}",
+                lines
+            );
+
+            // Tous les dossiers, avec leurs attributs
+            let lines = client.command("LIST \"\" \"*\" RETURN (SPECIAL-USE)");
+            assert_eq!(lines.len(), 6, "{:?}", lines);
+            assert!(contains(&lines, "* LIST () \"/\" INBOX"), "{:?}", lines);
+            assert!(contains(&lines, "* LIST () \"/\" Projets"), "{:?}", lines);
+            assert!(
+                contains(&lines, "* LIST (\\Sent) \"/\" Envoy&AOk-s"),
+                "{:?}",
+                lines
+            );
+
+            // L'option ne reste pas pour la commande suivante
+            let lines = client.command("LIST \"\" \"*\"");
+            assert_eq!(lines.len(), 6, "{:?}", lines);
+        });
+    }
+
+    #[test]
+    fn status_uids_match_select() {
+        let fake = Fake::new(32);
+        fake.receive(1, "Sortie scolaire", true);
+        fake.receive(2, "Devoirs de maths", false);
+        session(&fake, |client| {
+            client.login();
+            let lines = client.command("STATUS INBOX (UIDNEXT UIDVALIDITY MESSAGES)");
+            let status = lines
+                .iter()
+                .find(|line| line.starts_with("* STATUS"))
+                .unwrap()
+                .clone();
+
+            let lines = client.command("SELECT INBOX");
+            let code = |name: &str| {
+                let line = lines.iter().find(|line| line.contains(name)).unwrap();
+                line.split(['[', ']']).nth(1).unwrap().to_string()
+            };
+            let (validity, next) = (code("[UIDVALIDITY "), code("[UIDNEXT "));
+            assert_eq!(next, "UIDNEXT 3");
+            assert_eq!(
+                status.trim_end(),
+                format!("* STATUS INBOX ({} {} MESSAGES 2)", next, validity)
+            );
+
+            // Le dossier sélectionné garde les mêmes valeurs
+            let lines = client.command("STATUS INBOX (UIDVALIDITY UIDNEXT)");
+            assert!(
+                contains(&lines, &format!("* STATUS INBOX ({} {})", validity, next)),
+                "{:?}",
+                lines
+            );
+        });
+    }
+
+    #[test]
+    fn check_is_a_noop() {
+        let fake = Fake::new(33);
+        fake.receive(1, "Sortie scolaire", true);
+        session(&fake, |client| {
+            client.login();
+            client.command("SELECT INBOX");
+            assert_eq!(client.status("CHECK"), "OK CHECK completed");
+            assert!(client.status("FETCH 1 (FLAGS)").starts_with("OK"));
+        });
+    }
+
+    #[test]
+    fn failed_select_closes_the_mailbox() {
+        let fake = Fake::new(34);
+        fake.receive(1, "Sortie scolaire", true);
+        session(&fake, |client| {
+            client.login();
+            client.command("SELECT INBOX");
+            let status = client.status("SELECT Inexistant");
+            assert!(status.starts_with("NO"), "{}", status);
+            let status = client.status("FETCH 1 (FLAGS)");
+            assert!(!status.starts_with("OK"), "{}", status);
+
+            client.command("EXAMINE INBOX");
+            // Dossier inconnu de la connexion : il faut redemander la liste à l'API
+            fake.set_down(true);
+            let status = client.status("SELECT Archives");
+            assert!(status.starts_with("NO"), "{}", status);
+            let status = client.status("FETCH 1 (FLAGS)");
+            assert!(!status.starts_with("OK"), "{}", status);
+        });
+    }
+}
@@ -0,0 +1,137 @@
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime};
+
+use crate::api::Folder;
+use crate::uid::UidMap;
+use crate::{config, data_dir};
+
+// Données conservées entre les connexions pour un compte EcoleDirecte :
+//  - uids/<dossier> : associations identifiant -> UID (voir `UidMap`)
+//  - folders/<dossier>.json : dernière réponse de l'API pour le dossier
+//  - bodies/<identifiant>.eml : messages déjà construits
+//  - flags.json : drapeaux qu'EcoleDirecte ne conserve pas, par message
+//    (modifié sous le verrou de flags.lock)
+//  - subscriptions : dossiers auxquels le client est abonné, un par ligne
+pub struct Store {
+    dir: PathBuf,
+}
+
+// Écrit dans un fichier temporaire puis renomme, pour ne jamais laisser
+// un fichier à moitié écrit. Le nom temporaire est propre à chaque écriture :
+// deux connexions au même compte peuvent écrire le même fichier en même temps.
+fn write(path: &Path, contents: &[u8]) -> io::Result<()> {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let temporary = path.with_extension(format!(
+        "{}-{}.tmp",
+        process::id(),
+        COUNTER.fetch_add(1, Ordering::Relaxed)
+    ));
+    fs::write(&temporary, contents)?;
+    fs::rename(temporary, path)
+}
+
+impl Store {
+    pub fn open(account: u32) -> Store {
+        Store {
+            dir: data_dir().join(account.to_string()),
+        }
+    }
+
+    fn save(&self, path: PathBuf, contents: &[u8]) {
+        if let Err(error) = write(&path, contents) {
+            log::warn!("Impossible d'écrire {}: {}", path.display(), error);
+        }
+    }
+
+    pub fn uids(&self, mailbox: &str) -> io::Result<UidMap> {
+        UidMap::open(self.dir.join("uids").join(mailbox))
+    }
+
+    // La dernière réponse de l'API pour ce dossier, si elle est assez récente
+    pub fn folder(&self, mailbox: &str) -> Option<Folder> {
+        let path = self.dir.join("folders").join(format!("{}.json", mailbox));
+        let age = fs::metadata(&path)
+            .and_then(|metadata| metadata.modified())
+            .ok()
+            .and_then(|modified| SystemTime::now().duration_since(modified).ok())?;
+        // Au-delà, on redemande la liste des messages à l'API
+        if age > Duration::from_secs(config::get().polling.cache_max_age) {
+            return None;
+        }
+        serde_json::from_slice(&fs::read(path).ok()?).ok()
+    }
+
+    pub fn save_folder(&self, mailbox: &str, folder: &Folder) {
+        // unwrap: une réponse déjà désérialisée se sérialise toujours
+        self.save(
+            self.dir.join("folders").join(format!("{}.json", mailbox)),
+            &serde_json::to_vec(folder).unwrap(),
+        );
+    }
+
+    // À appeler quand on modifie un message : la réponse en cache n'est plus à jour
+    pub fn forget_folder(&self, mailbox: &str) {
+        let _ = fs::remove_file(self.dir.join("folders").join(format!("{}.json", mailbox)));
+    }
+
+    pub fn body(&self, id: u64) -> Option<Vec<u8>> {
+        fs::read(self.dir.join("bodies").join(format!("{}.eml", id))).ok()
+    }
+
+    pub fn save_body(&self, id: u64, raw: &[u8]) {
+        self.save(self.dir.join("bodies").join(format!("{}.eml", id)), raw);
+    }
+
+    pub fn flags(&self) -> HashMap<u64, Vec<String>> {
+        fs::read(self.dir.join("flags.json"))
+            .ok()
+            .and_then(|flags| serde_json::from_slice(&flags).ok())
+            .unwrap_or_default()
+    }
+
+    // Relit et réécrit les drapeaux sous verrou, pour ne pas perdre ceux
+    // qu'une autre connexion enregistre en même temps
+    pub fn update_flags(&self, change: impl FnOnce(&mut HashMap<u64, Vec<String>>)) {
+        // Le verrou est libéré à la fermeture du fichier
+        let lock = fs::create_dir_all(&self.dir)
+            .and_then(|_| File::create(self.dir.join("flags.lock")))
+            .and_then(|file| file.lock().map(|_| file));
+        if let Err(error) = &lock {
+            log::warn!("Impossible de verrouiller flags.lock: {}", error);
+        }
+
+        let mut flags = self.flags();
+        change(&mut flags);
+        // unwrap: une table de chaînes se sérialise toujours
+        self.save(
+            self.dir.join("flags.json"),
+            &serde_json::to_vec(&flags).unwrap(),
+        );
+    }
+
+    // Sans fichier, le client est abonné à tous les dossiers
+    pub fn subscriptions(&self) -> Option<Vec<String>> {
+        let subscriptions = fs::read_to_string(self.dir.join("subscriptions")).ok()?;
+        Some(
+            subscriptions
+                .lines()
+                .filter(|line| !line.is_empty())
+                .map(str::to_string)
+                .collect(),
+        )
+    }
+
+    pub fn save_subscriptions(&self, subscriptions: &[String]) {
+        let mut contents = subscriptions.join("\n");
+        contents.push('\n');
+        self.save(self.dir.join("subscriptions"), contents.as_bytes());
+    }
+}
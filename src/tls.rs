@@ -0,0 +1,139 @@
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::ServerConfig;
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::{Arc, RwLock};
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpStream;
+use tokio_rustls::{server::TlsStream, TlsAcceptor};
+
+// Certificat et clé du serveur, relus depuis leurs fichiers par `reload` (à la
+// réception de SIGHUP, voir main.rs). Les connexions déjà chiffrées gardent
+// l'ancien certificat.
+pub struct Tls {
+    certificate: PathBuf,
+    key: PathBuf,
+    config: RwLock<Arc<ServerConfig>>,
+}
+
+fn load_config(certificate: &Path, key: &Path) -> Result<ServerConfig, String> {
+    let open = |path: &Path| {
+        File::open(path)
+            .map(BufReader::new)
+            .map_err(|error| format!("Impossible de lire {}: {}", path.display(), error))
+    };
+
+    let certificates = rustls_pemfile::certs(&mut open(certificate)?)
+        .collect::<Result<Vec<CertificateDer>, _>>()
+        .map_err(|error| format!("{}: {}", certificate.display(), error))?;
+    if certificates.is_empty() {
+        return Err(format!("{}: aucun certificat", certificate.display()));
+    }
+    let private_key: PrivateKeyDer = rustls_pemfile::private_key(&mut open(key)?)
+        .map_err(|error| format!("{}: {}", key.display(), error))?
+        .ok_or_else(|| format!("{}: aucune clé privée", key.display()))?;
+
+    ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certificates, private_key)
+        .map_err(|error| format!("{}: {}", certificate.display(), error))
+}
+
+impl Tls {
+    pub fn load(certificate: &Path, key: &Path) -> Result<Tls, String> {
+        Ok(Tls {
+            certificate: certificate.to_path_buf(),
+            key: key.to_path_buf(),
+            config: RwLock::new(Arc::new(load_config(certificate, key)?)),
+        })
+    }
+
+    // En cas d'erreur, on garde le certificat précédent
+    pub fn reload(&self) -> Result<(), String> {
+        let config = load_config(&self.certificate, &self.key)?;
+        // unwrap: personne ne panique en tenant le verrou
+        *self.config.write().unwrap() = Arc::new(config);
+        Ok(())
+    }
+
+    fn config(&self) -> Arc<ServerConfig> {
+        // unwrap: personne ne panique en tenant le verrou
+        self.config.read().unwrap().clone()
+    }
+}
+
+// Connexion avec le client, en clair ou chiffrée (IMAPS ou après STARTTLS)
+pub enum Stream {
+    Plain(TcpStream),
+    Tls(Box<TlsStream<TcpStream>>),
+}
+
+impl From<TcpStream> for Stream {
+    fn from(stream: TcpStream) -> Stream {
+        Stream::Plain(stream)
+    }
+}
+
+impl Stream {
+    // Connexion chiffrée dès le départ (IMAPS)
+    pub async fn accept(socket: TcpStream, tls: &Tls) -> io::Result<Stream> {
+        let stream = TlsAcceptor::from(tls.config()).accept(socket).await?;
+        Ok(Stream::Tls(Box::new(stream)))
+    }
+
+    // STARTTLS. Ce qui a été envoyé en clair avant la négociation doit avoir
+    // été ignoré par l'appelant.
+    pub async fn start_tls(self, tls: &Tls) -> io::Result<Stream> {
+        match self {
+            Stream::Plain(socket) => Stream::accept(socket, tls).await,
+            Stream::Tls(_) => Err(io::Error::other("already encrypted")),
+        }
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        matches!(self, Stream::Tls(_))
+    }
+}
+
+impl AsyncRead for Stream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buffer: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Stream::Plain(socket) => Pin::new(socket).poll_read(cx, buffer),
+            Stream::Tls(stream) => Pin::new(stream.as_mut()).poll_read(cx, buffer),
+        }
+    }
+}
+
+impl AsyncWrite for Stream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buffer: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Stream::Plain(socket) => Pin::new(socket).poll_write(cx, buffer),
+            Stream::Tls(stream) => Pin::new(stream.as_mut()).poll_write(cx, buffer),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Stream::Plain(socket) => Pin::new(socket).poll_flush(cx),
+            Stream::Tls(stream) => Pin::new(stream.as_mut()).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Stream::Plain(socket) => Pin::new(socket).poll_shutdown(cx),
+            Stream::Tls(stream) => Pin::new(stream.as_mut()).poll_shutdown(cx),
+        }
+    }
+}
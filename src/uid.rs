@@ -0,0 +1,184 @@
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, OpenOptions};
+use std::io::{self, ErrorKind, Read, Write};
+use std::num::NonZeroU32;
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::api::MessageSummary;
+
+// Association identifiant EcoleDirecte -> UID pour un dossier.
+//
+// Le fichier est en ajout seul : la première ligne contient l'UIDVALIDITY et
+// chaque ligne suivante une association "identifiant uid", ou "identifiant -"
+// quand le message a quitté le dossier. Comme on ne retire jamais de ligne,
+// UIDNEXT se déduit du plus grand UID attribué.
+//
+// Plusieurs connexions au même compte ont chacune leur `UidMap` : avant
+// d'attribuer des UIDs, on verrouille le fichier et on le relit pour ne pas
+// donner un UID déjà attribué ailleurs.
+pub struct UidMap {
+    pub validity: NonZeroU32,
+    pub next: NonZeroU32,
+    uids: HashMap<u64, NonZeroU32>,
+    // Messages qui ont quitté le dossier : ils gardent leur UID tant qu'une
+    // connexion les affiche, mais en auront un nouveau s'ils reviennent
+    gone: HashSet<u64>,
+    path: PathBuf,
+}
+
+fn new_validity() -> NonZeroU32 {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(1, |now| now.as_secs() as u32);
+    NonZeroU32::new(now).unwrap_or(NonZeroU32::MIN)
+}
+
+impl UidMap {
+    // Crée le fichier s'il n'existe pas, sous le même verrou que `append` pour
+    // qu'une autre connexion ne le crée pas en même temps. En cas d'erreur, le
+    // fichier est laissé tel quel : le réécrire ferait perdre tous les UIDs.
+    pub fn open(path: PathBuf) -> io::Result<UidMap> {
+        let mut map = UidMap {
+            validity: NonZeroU32::MIN,
+            next: NonZeroU32::MIN,
+            uids: HashMap::new(),
+            gone: HashSet::new(),
+            path,
+        };
+
+        if let Some(parent) = map.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        // Le verrou est libéré à la fermeture du fichier
+        let mut file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(&map.path)?;
+        file.lock()?;
+        let mut contents = String::new();
+        file.read_to_string(&mut contents)?;
+        if contents.is_empty() {
+            // Si le fichier a disparu, les anciens UIDs ont pu être donnés par
+            // le client à d'autres messages : il faut une nouvelle validité
+            map.validity = new_validity();
+            file.write_all(format!("{}\n", map.validity).as_bytes())?;
+        } else {
+            map.load(&contents)?;
+        }
+        Ok(map)
+    }
+
+    fn load(&mut self, contents: &str) -> io::Result<()> {
+        let mut lines = contents.lines();
+        match lines.next().and_then(|line| line.trim().parse().ok()) {
+            Some(validity) => self.validity = validity,
+            None => {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidData,
+                    "UIDVALIDITY illisible",
+                ))
+            }
+        }
+
+        for line in lines {
+            let mut parts = line.split_whitespace();
+            let (Some(Ok(id)), Some(uid)) = (parts.next().map(str::parse::<u64>), parts.next())
+            else {
+                continue;
+            };
+            if uid == "-" {
+                self.gone.insert(id);
+            } else if let Ok(uid) = uid.parse::<NonZeroU32>() {
+                self.uids.insert(id, uid);
+                self.gone.remove(&id);
+                self.next = self.next.max(uid.saturating_add(1));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn get(&self, id: u64) -> Option<NonZeroU32> {
+        self.uids.get(&id).copied()
+    }
+
+    // Attribue un UID aux messages qui n'en ont pas encore, dans l'ordre de
+    // leurs identifiants, et note ceux qui ont quitté le dossier : s'ils y
+    // reviennent (déplacés depuis le site par exemple), le client a déjà vu
+    // disparaître leur ancien UID et il leur en faut un nouveau. `messages`
+    // est la liste complète du dossier.
+    pub fn assign(&mut self, messages: &[MessageSummary]) {
+        let listed: HashSet<u64> = messages.iter().map(|message| message.id).collect();
+        if self.changes(&listed) == (vec![], vec![]) {
+            return;
+        }
+        self.append(|map| map.changes(&listed));
+    }
+
+    // Les messages sans UID valable et ceux qui ne sont plus listés
+    fn changes(&self, listed: &HashSet<u64>) -> (Vec<u64>, Vec<u64>) {
+        let mut new: Vec<_> = listed
+            .iter()
+            .copied()
+            .filter(|id| !self.uids.contains_key(id) || self.gone.contains(id))
+            .collect();
+        new.sort_unstable();
+        let mut left: Vec<_> = self
+            .uids
+            .keys()
+            .copied()
+            .filter(|id| !listed.contains(id) && !self.gone.contains(id))
+            .collect();
+        left.sort_unstable();
+        (new, left)
+    }
+
+    // Donne de nouveaux UIDs aux messages, même s'ils en avaient déjà un (un
+    // message qui revient dans le dossier est un nouveau message pour IMAP).
+    // Les lignes ajoutées remplacent les anciennes à la prochaine lecture.
+    pub fn renew(&mut self, ids: &[u64]) -> Vec<NonZeroU32> {
+        if ids.is_empty() {
+            return vec![];
+        }
+        self.append(|_| (ids.to_vec(), vec![]))
+    }
+
+    // Relit le fichier verrouillé, puis attribue un UID à chaque identifiant
+    // choisi par `changes` d'après les associations à jour et note le départ
+    // des autres
+    fn append(&mut self, changes: impl FnOnce(&UidMap) -> (Vec<u64>, Vec<u64>)) -> Vec<NonZeroU32> {
+        // Le verrou est libéré à la fermeture du fichier
+        let file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .open(&self.path)
+            .and_then(|mut file| {
+                file.lock()?;
+                let mut contents = String::new();
+                file.read_to_string(&mut contents)?;
+                self.load(&contents)?;
+                Ok(file)
+            });
+
+        let (new, left) = changes(self);
+        let mut uids = vec![];
+        let mut lines = String::new();
+        for id in left {
+            self.gone.insert(id);
+            lines.push_str(&format!("{} -\n", id));
+        }
+        for id in new {
+            self.uids.insert(id, self.next);
+            self.gone.remove(&id);
+            uids.push(self.next);
+            lines.push_str(&format!("{} {}\n", id, self.next));
+            self.next = self.next.saturating_add(1);
+        }
+
+        if let Err(error) = file.and_then(|mut file| file.write_all(lines.as_bytes())) {
+            log::warn!("Impossible d'écrire {}: {}", self.path.display(), error);
+        }
+        uids
+    }
+}
@@ -0,0 +1,57 @@
+// Le binaire du serveur, lancé avec --check-config
+
+use std::fs;
+use std::path::PathBuf;
+use std::process::{Command, Output};
+
+fn check_config(name: &str, contents: &str) -> Output {
+    let path: PathBuf = std::env::temp_dir().join(format!(
+        "ecoledirecte-imap-{}-{}.toml",
+        name,
+        std::process::id()
+    ));
+    fs::write(&path, contents).unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_ecoledirecte-imap"))
+        .arg("--config")
+        .arg(&path)
+        .arg("--check-config")
+        .env_clear()
+        .output()
+        .unwrap();
+    fs::remove_file(&path).unwrap();
+    output
+}
+
+#[test]
+fn valid_config() {
+    let output = check_config("valide", "[server]\nport = 1993\n");
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        "Configuration valide\n"
+    );
+}
+
+#[test]
+fn invalid_config() {
+    let output = check_config(
+        "invalide",
+        "[server]\nlisten = []\nmax_literal_size = 1024\nmax_command_size = 512\n",
+    );
+    assert!(!output.status.success());
+    let errors = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        errors.contains("server.listen: aucune adresse"),
+        "{}",
+        errors
+    );
+    assert!(errors.contains("server.max_literal_size"), "{}", errors);
+    assert!(errors.contains("server.max_command_size"), "{}", errors);
+}
+
+#[test]
+fn unknown_key() {
+    let output = check_config("inconnue", "[server]\nportt = 1993\n");
+    assert!(!output.status.success());
+    assert!(!output.stderr.is_empty());
+}
@@ -0,0 +1,133 @@
+// Le client IMAP des tests, partagé par les tests unitaires du serveur (inclus
+// depuis src/lib.rs) et les tests de bout en bout. Chacun n'en utilise qu'une
+// partie.
+#![allow(dead_code)]
+
+use rustls::pki_types::{CertificateDer, ServerName};
+use rustls::{ClientConfig, ClientConnection, RootCertStore, StreamOwned};
+use std::future::Future;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Once};
+use std::thread;
+
+// Le `Store` écrit dans le dossier de données et IDLE interroge l'API chaque
+// seconde pour ne pas faire attendre les tests : on le fixe une seule fois,
+// avant que la configuration ne soit lue
+pub fn environment() {
+    static INIT: Once = Once::new();
+    INIT.call_once(|| {
+        let dir =
+            std::env::temp_dir().join(format!("ecoledirecte-imap-tests-{}", std::process::id()));
+        std::env::set_var("ECOLEDIRECTE_IMAP_DATA", dir);
+        std::env::set_var("ECOLEDIRECTE_IMAP_IDLE_INTERVAL", "1");
+    });
+}
+
+pub trait Socket: Read + Write + Send {}
+
+impl<T: Read + Write + Send> Socket for T {}
+
+pub struct Client {
+    reader: BufReader<Box<dyn Socket>>,
+    socket: TcpStream,
+    tag: u32,
+    // Identifiants acceptés par la messagerie, pour `login`
+    credentials: (&'static str, &'static str),
+}
+
+impl Client {
+    // Chiffre la connexion, en ne faisant confiance qu'à `certificate`
+    pub fn encrypt(&mut self, certificate: &CertificateDer<'static>) {
+        let mut roots = RootCertStore::empty();
+        roots.add(certificate.clone()).unwrap();
+        let config = ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        let connection =
+            ClientConnection::new(Arc::new(config), ServerName::try_from("localhost").unwrap())
+                .unwrap();
+        let stream = StreamOwned::new(connection, self.socket.try_clone().unwrap());
+        self.reader = BufReader::new(Box::new(stream));
+    }
+
+    pub fn write(&mut self, data: impl AsRef<[u8]>) {
+        self.reader.get_mut().write_all(data.as_ref()).unwrap();
+    }
+
+    pub fn line(&mut self) -> String {
+        let mut line = String::new();
+        self.reader.read_line(&mut line).unwrap();
+        line
+    }
+
+    // Les réponses à la commande, jusqu'à celle qui porte son étiquette
+    pub fn command(&mut self, command: &str) -> Vec<String> {
+        self.tag += 1;
+        let tag = format!("A{} ", self.tag);
+        self.write(format!("{}{}\r\n", tag, command));
+        let mut lines = vec![];
+        loop {
+            let line = self.line();
+            assert!(!line.is_empty(), "connection closed during {}", command);
+            let done = line.starts_with(&tag);
+            lines.push(line);
+            if done {
+                return lines;
+            }
+        }
+    }
+
+    // La réponse étiquetée, sans l'étiquette
+    pub fn status(&mut self, command: &str) -> String {
+        let line = self.command(command).pop().unwrap();
+        line.split_once(' ').unwrap().1.trim_end().to_string()
+    }
+
+    pub fn login(&mut self) {
+        let (username, password) = self.credentials;
+        let status = self.status(&format!("LOGIN {} {}", username, password));
+        assert!(status.starts_with("OK"), "{}", status);
+    }
+}
+
+// Fait tourner `server` sur une connexion locale, dans un runtime à lui,
+// pendant que `test` joue le client. En IMAPS, `implicit` donne le certificat
+// et le test reçoit la connexion déjà chiffrée.
+pub fn serve<F: Future<Output = ()>>(
+    credentials: (&'static str, &'static str),
+    implicit: Option<&CertificateDer<'static>>,
+    server: impl FnOnce(tokio::net::TcpStream) -> F + Send,
+    test: impl FnOnce(&mut Client),
+) {
+    environment();
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap();
+    thread::scope(|s| {
+        s.spawn(|| {
+            let (socket, _) = listener.accept().unwrap();
+            socket.set_nonblocking(true).unwrap();
+            let runtime = tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .unwrap();
+            runtime.block_on(async {
+                server(tokio::net::TcpStream::from_std(socket).unwrap()).await;
+            });
+        });
+
+        let socket = TcpStream::connect(address).unwrap();
+        let mut client = Client {
+            reader: BufReader::new(Box::new(socket.try_clone().unwrap())),
+            socket,
+            tag: 0,
+            credentials,
+        };
+        if let Some(certificate) = implicit {
+            client.encrypt(certificate);
+        }
+        assert!(client.line().starts_with("* OK"));
+        test(&mut client);
+        assert!(client.status("LOGOUT").starts_with("OK"));
+    });
+}
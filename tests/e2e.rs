@@ -0,0 +1,274 @@
+// Sessions IMAP complètes contre le faux serveur EcoleDirecte (voir `mock`)
+
+mod common;
+mod mock;
+
+use base64::{engine::general_purpose::STANDARD as base64, Engine};
+use common::Client;
+use ecoledirecte_imap::api;
+use ecoledirecte_imap::backend::EcoleDirecte;
+use ecoledirecte_imap::server::{responder, Connection};
+use ecoledirecte_imap::tls::{Stream, Tls};
+use mock::Mock;
+use reqwest::Url;
+use rustls::pki_types::CertificateDer;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+fn contains(lines: &[String], expected: &str) -> bool {
+    lines.iter().any(|line| line.contains(expected))
+}
+
+// Fait tourner le serveur IMAP sur une connexion locale, avec le faux
+// serveur comme API
+fn session(mock: &Mock, test: impl FnOnce(&mut Client)) {
+    session_with(mock_settings(mock), test);
+}
+
+fn session_with(settings: api::Settings, test: impl FnOnce(&mut Client)) {
+    serve(settings, None, test);
+}
+
+// Certificat auto-signé pour localhost, écrit dans `dir`
+fn self_signed(dir: &str) -> (CertificateDer<'static>, PathBuf, PathBuf) {
+    let dir =
+        std::env::temp_dir().join(format!("ecoledirecte-imap-{}-{}", dir, std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let generated = rcgen::generate_simple_self_signed(vec![String::from("localhost")]).unwrap();
+    let (certificate, key) = (dir.join("cert.pem"), dir.join("key.pem"));
+    fs::write(&certificate, generated.cert.pem()).unwrap();
+    fs::write(&key, generated.key_pair.serialize_pem()).unwrap();
+    (generated.cert.der().clone(), certificate, key)
+}
+
+// Comme `session`, avec STARTTLS ou en IMAPS (`implicit`) si on donne un
+// certificat. En IMAPS, le test reçoit la connexion déjà chiffrée.
+fn serve(
+    settings: api::Settings,
+    tls: Option<(&Arc<Tls>, &CertificateDer<'static>, bool)>,
+    test: impl FnOnce(&mut Client),
+) {
+    let credentials = (mock::USERNAME, mock::PASSWORD);
+    let implicit = tls.filter(|(_, _, implicit)| *implicit);
+    let server = |socket| async move {
+        let backend = EcoleDirecte::new(api::Client::new(settings).await);
+        let stream = match tls {
+            Some((tls, _, true)) => Stream::accept(socket, tls).await.unwrap(),
+            _ => Stream::from(socket),
+        };
+        let connection = Connection::new(tls.map(|(tls, _, _)| tls.clone()));
+        responder(stream, connection, &backend).await;
+    };
+    common::serve(credentials, implicit.map(|(_, der, _)| der), server, test);
+}
+
+fn mock_settings(mock: &Mock) -> api::Settings {
+    api::Settings {
+        base_url: Url::parse(&mock.url).unwrap(),
+        ..api::Settings::default()
+    }
+}
+
+#[test]
+fn browse_mailbox() {
+    let mock = Mock::start(101);
+    session(&mock, |client| {
+        let status = client.status(&format!("LOGIN {} wrong", mock::USERNAME));
+        assert!(
+            status.starts_with("NO [AUTHENTICATIONFAILED]"),
+            "{}",
+            status
+        );
+        client.login();
+
+        let lines = client.command("LIST \"\" \"*\"");
+        assert!(contains(&lines, "\"/\" Sorties"), "{:?}", lines);
+        assert!(
+            contains(&lines, "(\\Archive) \"/\" Archived"),
+            "{:?}",
+            lines
+        );
+
+        let lines = client.command("SELECT INBOX");
+        assert!(contains(&lines, "* 2 EXISTS"), "{:?}", lines);
+
+        // Dans l'ordre des identifiants : 101 puis 102
+        let lines = client.command("FETCH 1:* (FLAGS BODY.PEEK[HEADER.FIELDS (FROM)])");
+        assert!(contains(&lines, "* 1 FETCH (FLAGS (\\Seen)"), "{:?}", lines);
+        assert!(contains(&lines, "* 2 FETCH (FLAGS ()"), "{:?}", lines);
+        assert!(
+            contains(
+                &lines,
+                "From: \"M. Jean DUPONT\" <p12@ecoledirecte.invalid>"
+            ),
+            "{:?}",
+            lines
+        );
+
+        let lines = client.command("FETCH 1 (BODYSTRUCTURE BODY[2])");
+        assert!(contains(&lines, "\"autorisation.pdf\""), "{:?}", lines);
+        assert!(
+            contains(&lines, &base64.encode(mock::ATTACHMENT)),
+            "{:?}",
+            lines
+        );
+    });
+}
+
+#[test]
+fn flags_reach_ecoledirecte() {
+    let mock = Mock::start(102);
+    session(&mock, |client| {
+        client.login();
+        client.command("SELECT INBOX");
+
+        // BODY.PEEK[] ne change rien, même si l'API marque le message comme lu
+        client.command("FETCH 2 BODY.PEEK[]");
+        assert!(!mock.is_read(102));
+
+        assert!(client.status("STORE 2 +FLAGS (\\Seen)").starts_with("OK"));
+        assert!(mock.is_read(102));
+        assert!(client.status("STORE 1 -FLAGS (\\Seen)").starts_with("OK"));
+        assert!(!mock.is_read(101));
+
+        let lines = client.command("STATUS INBOX (UNSEEN)");
+        assert!(contains(&lines, "(UNSEEN 1)"), "{:?}", lines);
+    });
+}
+
+#[test]
+fn expired_token_is_renewed() {
+    let mock = Mock::start(103);
+    session(&mock, |client| {
+        client.login();
+        mock.expire_token();
+        let lines = client.command("SELECT INBOX");
+        assert!(contains(&lines, "* 2 EXISTS"), "{:?}", lines);
+        assert_eq!(mock.logins(), 2);
+    });
+}
+
+#[test]
+fn attachment_error_is_not_cached() {
+    let mock = Mock::start(108);
+    session(&mock, |client| {
+        client.login();
+        client.command("SELECT INBOX");
+
+        mock.set_attachment_error(Some(520));
+        let status = client.status("FETCH 1 BODY.PEEK[2]");
+        assert!(status.starts_with("NO"), "{}", status);
+
+        mock.set_attachment_error(None);
+        let lines = client.command("FETCH 1 BODY.PEEK[2]");
+        assert!(
+            contains(&lines, &base64.encode(mock::ATTACHMENT)),
+            "{:?}",
+            lines
+        );
+    });
+}
+
+#[test]
+fn corrupt_content_is_not_cached() {
+    let mock = Mock::start(109);
+    session(&mock, |client| {
+        client.login();
+        client.command("SELECT INBOX");
+
+        mock.set_corrupt_content(true);
+        let status = client.status("FETCH 2 BODY.PEEK[]");
+        assert!(status.starts_with("NO [SERVERBUG]"), "{}", status);
+
+        mock.set_corrupt_content(false);
+        let lines = client.command("FETCH 2 BODY.PEEK[]");
+        // "<p>Exercices 3 et 4 page 52.</p>"
+        assert!(
+            contains(&lines, "PHA+RXhlcmNpY2VzIDMgZXQgNCBwYWdlIDUyLjwvcD4="),
+            "{:?}",
+            lines
+        );
+    });
+}
+
+#[test]
+fn unavailable_api() {
+    let mock = Mock::start(104);
+    session(&mock, |client| {
+        client.login();
+        mock.set_status(Some(503));
+        let status = client.status("SELECT INBOX");
+        assert!(status.starts_with("NO [UNAVAILABLE]"), "{}", status);
+
+        mock.set_status(None);
+        let lines = client.command("SELECT INBOX");
+        assert!(contains(&lines, "* 2 EXISTS"), "{:?}", lines);
+    });
+}
+
+#[test]
+fn api_settings_are_used() {
+    let mock = Mock::start(105);
+    let settings = api::Settings {
+        base_url: Url::parse(&mock.url).unwrap(),
+        version: String::from("9.9.9"),
+        user_agent: String::from("Mozilla/5.0"),
+    };
+    session_with(settings, |client| {
+        client.login();
+        assert_eq!(
+            mock.last_client(),
+            (String::from("9.9.9"), String::from("Mozilla/5.0"))
+        );
+    });
+}
+
+#[test]
+fn starttls_protects_the_password() {
+    let mock = Mock::start(106);
+    let (der, certificate, key) = self_signed("starttls");
+    let tls = Arc::new(Tls::load(&certificate, &key).unwrap());
+    serve(mock_settings(&mock), Some((&tls, &der, false)), |client| {
+        let lines = client.command("CAPABILITY");
+        assert!(contains(&lines, " STARTTLS"), "{:?}", lines);
+        assert!(contains(&lines, " LOGINDISABLED"), "{:?}", lines);
+        assert!(!contains(&lines, "AUTH=PLAIN"), "{:?}", lines);
+        let status = client.status(&format!("LOGIN {} {}", mock::USERNAME, mock::PASSWORD));
+        assert!(status.starts_with("NO [PRIVACYREQUIRED]"), "{}", status);
+        assert_eq!(mock.logins(), 0);
+
+        // La commande envoyée en clair derrière STARTTLS ne doit pas être exécutée
+        client.write("S1 STARTTLS\r\nS2 NOOP\r\n");
+        assert!(client.line().starts_with("S1 OK"));
+        client.encrypt(&der);
+
+        let lines = client.command("CAPABILITY");
+        assert!(lines[0].starts_with("* CAPABILITY"), "{:?}", lines);
+        assert!(!contains(&lines, "STARTTLS"), "{:?}", lines);
+        assert!(contains(&lines, "AUTH=PLAIN"), "{:?}", lines);
+        client.login();
+        let lines = client.command("SELECT INBOX");
+        assert!(contains(&lines, "* 2 EXISTS"), "{:?}", lines);
+    });
+}
+
+#[test]
+fn imaps_and_certificate_reload() {
+    let mock = Mock::start(107);
+    let (old, certificate, key) = self_signed("imaps");
+    let tls = Arc::new(Tls::load(&certificate, &key).unwrap());
+    serve(mock_settings(&mock), Some((&tls, &old, true)), |client| {
+        let lines = client.command("CAPABILITY");
+        assert!(!contains(&lines, "STARTTLS"), "{:?}", lines);
+        assert!(client.status("STARTTLS").starts_with("BAD"));
+        client.login();
+    });
+
+    // Le nouveau certificat remplace l'ancien pour les connexions suivantes
+    let (new, _, _) = self_signed("imaps");
+    tls.reload().unwrap();
+    serve(mock_settings(&mock), Some((&tls, &new, true)), |client| {
+        client.login();
+    });
+}
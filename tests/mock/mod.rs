@@ -0,0 +1,325 @@
+// Un faux serveur EcoleDirecte pour les tests de bout en bout : il sert les
+// routes de connexion, de la messagerie et du contenu des messages à partir
+// des fichiers de tests/fixtures.
+//
+// Comme le vrai, il donne un nouveau jeton à chaque réponse et refuse les
+// anciens (code 525).
+
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+pub const USERNAME: &str = "camille.martin";
+pub const PASSWORD: &str = "motdepasse";
+
+// Le contenu de la pièce jointe 55 de message-101.json
+pub const ATTACHMENT: &[u8] = b"%PDF-1.4 autorisation";
+
+fn fixture(name: &str) -> Value {
+    let fixture = match name {
+        "login" => include_str!("../fixtures/login.json"),
+        "messages" => include_str!("../fixtures/messages.json"),
+        "message-101" => include_str!("../fixtures/message-101.json"),
+        "message-102" => include_str!("../fixtures/message-102.json"),
+        _ => return Value::Null,
+    };
+    serde_json::from_str(fixture).unwrap()
+}
+
+struct State {
+    account: u32,
+    // Le seul jeton accepté
+    token: Option<String>,
+    issued: u32,
+    logins: u32,
+    // Réponse de la messagerie, modifiée par les actions
+    messages: Value,
+    // Statut HTTP de toutes les réponses, pour simuler une panne
+    status: Option<u16>,
+    // Code d'erreur EcoleDirecte renvoyé à la place des pièces jointes
+    attachment_error: Option<u64>,
+    // Contenu des messages qui n'est pas du base64
+    corrupt_content: bool,
+    // Version (`v=`) et User-Agent de la dernière requête
+    client: (String, String),
+}
+
+impl State {
+    fn new_token(&mut self) -> String {
+        self.issued += 1;
+        let token = format!("jeton-{}", self.issued);
+        self.token = Some(token.clone());
+        token
+    }
+
+    fn received(&mut self) -> &mut Vec<Value> {
+        self.messages["data"]["messages"]["received"]
+            .as_array_mut()
+            .unwrap()
+    }
+}
+
+pub struct Mock {
+    pub url: String,
+    state: Arc<Mutex<State>>,
+}
+
+struct Request {
+    path: String,
+    query: HashMap<String, String>,
+    token: String,
+    user_agent: String,
+    data: Value,
+}
+
+fn read_request(stream: &TcpStream) -> Option<Request> {
+    let mut reader = BufReader::new(stream);
+    let mut line = String::new();
+    reader.read_line(&mut line).ok()?;
+    let target = line.split(' ').nth(1)?.to_string();
+
+    let mut length = 0;
+    let mut token = String::new();
+    let mut user_agent = String::new();
+    loop {
+        let mut header = String::new();
+        reader.read_line(&mut header).ok()?;
+        let header = header.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        let (name, value) = header.split_once(':')?;
+        match name.to_ascii_lowercase().as_str() {
+            "content-length" => length = value.trim().parse().ok()?,
+            "x-token" => token = value.trim().to_string(),
+            "user-agent" => user_agent = value.trim().to_string(),
+            _ => (),
+        }
+    }
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body).ok()?;
+
+    let (path, query) = target.split_once('?').unwrap_or((&target, ""));
+    let query = query
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .map(|(name, value)| (name.to_string(), value.to_string()))
+        .collect();
+    let body = String::from_utf8(body).ok()?;
+    let data = serde_json::from_str(body.strip_prefix("data=")?).ok()?;
+    Some(Request {
+        path: path.to_string(),
+        query,
+        token,
+        user_agent,
+        data,
+    })
+}
+
+fn write_response(mut stream: &TcpStream, status: u16, content_type: &str, body: &[u8]) {
+    let head = format!(
+        "HTTP/1.1 {} Mock\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        status,
+        content_type,
+        body.len()
+    );
+    let _ = stream.write_all(&[head.as_bytes(), body].concat());
+}
+
+fn error(code: u64, message: &str) -> Value {
+    json!({ "code": code, "token": "", "message": message, "data": {} })
+}
+
+fn login(state: &mut State, request: &Request) -> Value {
+    let identifiant = request.data["identifiant"].as_str();
+    let motdepasse = request.data["motdepasse"].as_str();
+    if identifiant != Some(USERNAME) || motdepasse != Some(PASSWORD) {
+        return error(505, "Identifiant et/ou mot de passe invalide !");
+    }
+    state.logins += 1;
+    let mut response = fixture("login");
+    response["data"]["accounts"][0]["id"] = state.account.into();
+    response["token"] = state.new_token().into();
+    response
+}
+
+// Marquer comme lu, archiver... sur les messages reçus
+fn action(state: &mut State, request: &Request) -> Value {
+    let ids: Vec<u64> = request.data["ids"]
+        .as_array()
+        .map(|ids| ids.iter().filter_map(Value::as_u64).collect())
+        .unwrap_or_default();
+    let read = match request.data["action"].as_str() {
+        Some("marquerCommeLu") => true,
+        Some("marquerCommeNonLu") => false,
+        _ => return json!({ "code": 200, "data": {} }),
+    };
+    for message in state.received() {
+        if ids.contains(&message["id"].as_u64().unwrap()) {
+            message["read"] = read.into();
+        }
+    }
+    let unread = state
+        .received()
+        .iter()
+        .filter(|message| message["read"] == false)
+        .count();
+    state.messages["data"]["pagination"]["messagesRecusNotReadCount"] = unread.into();
+    json!({ "code": 200, "data": {} })
+}
+
+fn message(state: &mut State, id: u64) -> Value {
+    let mut response = fixture(&format!("message-{}", id));
+    if response.is_null() {
+        return error(210, "Message introuvable");
+    }
+    // Comme EcoleDirecte, le message est marqué comme lu
+    for message in state.received() {
+        if message["id"] == id {
+            message["read"] = true.into();
+        }
+    }
+    response["data"]["read"] = true.into();
+    if state.corrupt_content {
+        response["data"]["content"] = "pas du base64 !".into();
+    }
+    response
+}
+
+fn handle(state: &Mutex<State>, stream: TcpStream) {
+    let Some(request) = read_request(&stream) else {
+        return write_response(&stream, 400, "text/plain", b"bad request");
+    };
+    let mut state = state.lock().unwrap();
+    let version = request.query.get("v").cloned().unwrap_or_default();
+    state.client = (version, request.user_agent.clone());
+    if let Some(status) = state.status {
+        return write_response(&stream, status, "text/plain", b"unavailable");
+    }
+
+    if request.path == "/v3/login.awp" {
+        let response = login(&mut state, &request);
+        return write_response(
+            &stream,
+            200,
+            "application/json",
+            response.to_string().as_bytes(),
+        );
+    }
+
+    if state.token.as_deref() != Some(request.token.as_str()) {
+        let response = error(525, "Token invalide !");
+        return write_response(
+            &stream,
+            200,
+            "application/json",
+            response.to_string().as_bytes(),
+        );
+    }
+    let token = state.new_token();
+
+    let messages = format!("/v3/eleves/{}/messages", state.account);
+    let verbe = request.query.get("verbe").map_or("", String::as_str);
+    let mut response = if request.path == "/v3/telechargement.awp" {
+        if let Some(code) = state.attachment_error {
+            let response = error(code, "Erreur lors du téléchargement");
+            return write_response(
+                &stream,
+                200,
+                "application/json",
+                response.to_string().as_bytes(),
+            );
+        }
+        return write_response(&stream, 200, "application/octet-stream", ATTACHMENT);
+    } else if request.path == format!("{}.awp", messages) && verbe == "get" {
+        let mut response = state.messages.clone();
+        // Les classeurs sont vides
+        if request.query.get("idClasseur").map_or("0", String::as_str) != "0" {
+            response["data"]["messages"]["received"] = json!([]);
+        }
+        response
+    } else if request.path == format!("{}.awp", messages) {
+        action(&mut state, &request)
+    } else if let Some(id) = request
+        .path
+        .strip_prefix(&format!("{}/", messages))
+        .and_then(|path| path.strip_suffix(".awp"))
+        .and_then(|id| id.parse().ok())
+    {
+        message(&mut state, id)
+    } else {
+        return write_response(&stream, 404, "text/plain", b"not found");
+    };
+
+    response["token"] = token.into();
+    write_response(
+        &stream,
+        200,
+        "application/json",
+        response.to_string().as_bytes(),
+    );
+}
+
+impl Mock {
+    // Démarre le serveur sur un port libre. Chaque test utilise son propre
+    // compte pour ne pas partager le `Store`.
+    pub fn start(account: u32) -> Mock {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        let state = Arc::new(Mutex::new(State {
+            account,
+            token: None,
+            issued: 0,
+            logins: 0,
+            messages: fixture("messages"),
+            status: None,
+            attachment_error: None,
+            corrupt_content: false,
+            client: Default::default(),
+        }));
+
+        let shared = Arc::clone(&state);
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                handle(&shared, stream);
+            }
+        });
+        Mock { url, state }
+    }
+
+    // Le jeton en cours n'est plus accepté
+    pub fn expire_token(&self) {
+        self.state.lock().unwrap().token = None;
+    }
+
+    pub fn set_status(&self, status: Option<u16>) {
+        self.state.lock().unwrap().status = status;
+    }
+
+    pub fn set_attachment_error(&self, code: Option<u64>) {
+        self.state.lock().unwrap().attachment_error = code;
+    }
+
+    pub fn set_corrupt_content(&self, corrupt: bool) {
+        self.state.lock().unwrap().corrupt_content = corrupt;
+    }
+
+    pub fn last_client(&self) -> (String, String) {
+        self.state.lock().unwrap().client.clone()
+    }
+
+    pub fn logins(&self) -> u32 {
+        self.state.lock().unwrap().logins
+    }
+
+    pub fn is_read(&self, id: u64) -> bool {
+        let mut state = self.state.lock().unwrap();
+        state
+            .received()
+            .iter()
+            .any(|message| message["id"] == id && message["read"] == true)
+    }
+}